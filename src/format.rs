@@ -0,0 +1,97 @@
+use std::path::Path;
+
+use serde::Serialize;
+use serde_json::Value;
+
+/// File format of the database, picked from the file extension unless `--format` is given
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Json5,
+    Yaml,
+    Toml,
+}
+
+impl Format {
+    /// Unknown extensions are read as json
+    pub fn from_path(path: &str) -> Format {
+        let extension = Path::new(path)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("json5") => Format::Json5,
+            Some("yaml") | Some("yml") => Format::Yaml,
+            Some("toml") => Format::Toml,
+            _ => Format::Json,
+        }
+    }
+
+    /// Extension of the files of a database directory
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Json5 => "json5",
+            Format::Yaml => "yaml",
+            Format::Toml => "toml",
+        }
+    }
+
+    pub fn parse(&self, content: &str) -> Result<Value, String> {
+        match self {
+            Format::Json => serde_json::from_str(content).map_err(|e| e.to_string()),
+            Format::Json5 => json5::from_str(content).map_err(|e| e.to_string()),
+            // an empty yaml document is null
+            Format::Yaml if content.trim().is_empty() => Ok(Value::Object(Default::default())),
+            Format::Yaml => serde_yaml::from_str(content).map_err(|e| e.to_string()),
+            Format::Toml => toml::from_str(content).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Toml can not hold null, a value holding one is an error naming its path
+    pub fn serialize<T: Serialize>(&self, value: &T) -> Result<String, String> {
+        match self {
+            Format::Json => serde_json::to_string(value).map_err(|e| e.to_string()),
+            Format::Json5 => json5::to_string(value).map_err(|e| e.to_string()),
+            Format::Yaml => serde_yaml::to_string(value).map_err(|e| e.to_string()),
+            Format::Toml => {
+                let value = serde_json::to_value(value).map_err(|e| e.to_string())?;
+                if let Some(path) = null_path(&value) {
+                    return Err(format!("toml can not hold the null at [{}]", path));
+                }
+                toml::to_string(&value).map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    /// Guesses whether `content` has comments, they are not kept when the database file is saved
+    pub fn has_comments(&self, content: &str) -> bool {
+        content.lines().any(|line| {
+            let line = line.trim_start();
+            match self {
+                Format::Json => false,
+                Format::Json5 => {
+                    line.starts_with("//") || line.starts_with("/*") || line.contains(" //")
+                }
+                Format::Yaml | Format::Toml => line.starts_with('#') || line.contains(" #"),
+            }
+        })
+    }
+}
+
+/// Dot-separated path of the first null in `value`, as `path::get` takes it
+fn null_path(value: &Value) -> Option<String> {
+    let child = |segment: String, value: &Value| match value {
+        Value::Null => Some(segment),
+        _ => null_path(value).map(|path| format!("{}.{}", segment, path)),
+    };
+    match value {
+        Value::Object(object) => object
+            .iter()
+            .find_map(|(key, value)| child(key.clone(), value)),
+        Value::Array(items) => items
+            .iter()
+            .enumerate()
+            .find_map(|(index, value)| child(index.to_string(), value)),
+        _ => None,
+    }
+}
@@ -1,43 +1,47 @@
-use std::{cmp::Ordering, collections::HashMap};
+use std::{cmp::Ordering, sync::atomic};
 
 use axum::{
     extract::{Path, Query, State},
-    http::{StatusCode, Uri},
+    http::{HeaderMap, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
-use rayon::prelude::*;
 use serde::Deserialize;
-use serde_json::{json, Value};
+use serde_json::Value;
 
-use super::{get_name, AppState};
-
-const DEFAULT_PAGE_SIZE: usize = 20;
+use super::{
+    filter::{FilterError, Predicate},
+    id,
+    patch::{is_json_patch, json_patch, merge_patch},
+    path, AppState,
+};
+use crate::store::{self, Order};
 
 pub async fn list(
-    uri: Uri,
+    Path(name): Path<String>,
     paginate: Option<Query<Paginate>>,
     sort: Option<Query<Sort>>,
+    fields: Option<Query<Fields>>,
     slice: Option<Query<Slice>>,
-    Query(params): Query<HashMap<String, String>>,
+    Query(params): Query<Vec<(String, String)>>,
     State(app_state): State<AppState>,
 ) -> impl IntoResponse {
-    let name = get_name(uri);
-    let db_value = app_state.db_value.read().await;
-    let values = db_value.get(&name).expect("key not found");
-    if !values.is_array() {
-        return Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body::<String>("key is not array".into())
-            .expect("failed to render response");
-    }
+    let page_size = match app_state.store.collection(&name).await {
+        Ok(collection) => collection.page_size,
+        Err((status, message)) => {
+            return Response::builder()
+                .status(status)
+                .body(message)
+                .expect("failed to render response")
+        }
+    };
     let (sorts, orders) = if let Some(sort) = sort {
-        let mut sorts = sort
+        let sorts = sort
             .sort
             .split(',')
             .map(|i| i.to_string())
             .collect::<Vec<String>>();
-        let mut orders = sort
+        let orders = sort
             .order
             .split(',')
             .map(|i| i.to_string())
@@ -48,8 +52,6 @@ pub async fn list(
                 .body("sort and order length not match".into())
                 .expect("failed to render response");
         }
-        sorts.reverse();
-        orders.reverse();
         (sorts, orders)
     } else {
         (Vec::new(), Vec::new())
@@ -81,388 +83,289 @@ pub async fn list(
         }
     }
 
-    let mut values_clone = values.clone();
-    let values = values_clone.as_array_mut().unwrap();
-
-    //1、filter
-    let filters = params
-        .par_iter()
-        .filter(|(k, _)| !k.starts_with('_'))
-        .map(|(k, v)| (k.to_string(), v.to_string()))
-        .collect::<Vec<(String, String)>>();
-    values.retain(|item| {
-        for (k, v) in filters.iter() {
-            if k.ends_with("_lte") && v.parse::<f64>().is_ok() {
-                let k = k.trim_end_matches("_lte");
-                if let Some(value) = item.get(k) {
-                    if value.is_number() && value.as_f64().unwrap() > v.parse::<f64>().unwrap() {
-                        return false;
-                    }
-                } else {
-                    return false;
-                }
-            } else if k.ends_with("_gte") && v.parse::<f64>().is_ok() {
-                let k = k.trim_end_matches("_gte");
-                if let Some(value) = item.get(k) {
-                    if value.is_number() && value.as_f64().unwrap() < v.parse::<f64>().unwrap() {
-                        return false;
-                    }
-                } else {
-                    return false;
-                }
-            } else if k.ends_with("_lt") && v.parse::<f64>().is_ok() {
-                let k = k.trim_end_matches("_lt");
-                if let Some(value) = item.get(k) {
-                    if value.is_number() && value.as_f64().unwrap() >= v.parse::<f64>().unwrap() {
-                        return false;
-                    }
-                } else {
-                    return false;
-                }
-            } else if k.ends_with("_gt") && v.parse::<f64>().is_ok() {
-                let k = k.trim_end_matches("_gt");
-                if let Some(value) = item.get(k) {
-                    if value.is_number() && value.as_f64().unwrap() <= v.parse::<f64>().unwrap() {
-                        return false;
-                    }
-                } else {
-                    return false;
-                }
-            } else if k.ends_with("_ne") {
-                let k = k.trim_end_matches("_ne");
-                if let Some(value) = item.get(k) {
-                    if (value.is_string() && value.as_str().unwrap() == v)
-                        || (value.is_number()
-                            && v.parse::<f64>().is_ok()
-                            && value.as_f64().unwrap() == v.parse::<f64>().unwrap())
-                        || (value.is_boolean()
-                            && value.as_bool().unwrap() == v.parse::<bool>().unwrap())
-                    {
-                        return false;
-                    }
-                } else {
-                    return false;
-                }
-            } else if k.ends_with("_like") {
-                let k = k.trim_end_matches("_like");
-                if let Some(value) = item.get(k) {
-                    if !value.is_string() || !value.as_str().unwrap().contains(v) {
-                        return false;
-                    }
-                } else {
-                    return false;
-                }
-            } else if k.ends_with("_nlike") {
-                let k = k.trim_end_matches("_nlike");
-                if let Some(value) = item.get(k) {
-                    if !value.is_string() || value.as_str().unwrap().contains(v) {
-                        return false;
-                    }
-                } else {
-                    return false;
-                }
-            } else if k.ends_with("_contains") {
-                let k = k.trim_end_matches("_contains");
-                if let Some(value) = item.get(k) {
-                    if !value.is_array()
-                        || !value
-                            .as_array()
-                            .unwrap()
-                            .contains(&Value::String(v.to_string()))
-                    {
-                        return false;
-                    }
-                } else {
-                    return false;
-                }
-            } else if k.ends_with("_ncontains") {
-                let k = k.trim_end_matches("_ncontains");
-                if let Some(value) = item.get(k) {
-                    if !value.is_array()
-                        || value
-                            .as_array()
-                            .unwrap()
-                            .contains(&Value::String(v.to_string()))
-                    {
-                        return false;
-                    }
-                } else {
-                    return false;
-                }
-            } else if k.ends_with("_exists") {
-                let k = k.trim_end_matches("_exists");
-                if item.get(k).is_none() {
-                    return false;
-                }
-            } else if k.ends_with("_nexists") {
-                let k = k.trim_end_matches("_nexists");
-                if item.get(k).is_some() {
-                    return false;
-                }
-            } else if let Some(value) = item.get(k) {
-                if (value.is_string() && value.as_str().unwrap() != v)
-                    || (value.is_number()
-                        && v.parse::<f64>().is_ok()
-                        && value.as_f64().unwrap() != v.parse::<f64>().unwrap())
-                    || (value.is_boolean()
-                        && v.parse::<bool>().is_ok()
-                        && value.as_bool().unwrap() != v.parse::<bool>().unwrap())
-                {
-                    return false;
-                }
-            } else {
-                return false;
-            }
-        }
-        true
-    });
-    //2、sort
-    for (sort, order) in sorts.iter().zip(orders.iter()) {
-        values.sort_by(|a, b| {
-            let a = a.get(sort);
-            let b = b.get(sort);
-            if a.is_none() && b.is_none() {
-                return Ordering::Equal;
-            } else if a.is_none() && b.is_some() {
-                if order == "asc" {
-                    return Ordering::Less;
-                } else {
-                    return Ordering::Greater;
-                }
-            } else if a.is_some() && b.is_none() {
-                if order == "asc" {
-                    return Ordering::Greater;
-                } else {
-                    return Ordering::Less;
-                }
-            }
-            let a = a.unwrap();
-            let b = b.unwrap();
-            if a.is_number() && b.is_number() {
-                let a = a.as_f64().unwrap();
-                let b = b.as_f64().unwrap();
-                if order == "asc" {
-                    a.partial_cmp(&b).unwrap()
-                } else {
-                    b.partial_cmp(&a).unwrap()
-                }
-            } else if a.is_string() && b.is_string() {
-                let a = a.as_str().unwrap();
-                let b = b.as_str().unwrap();
-                if order == "asc" {
-                    a.partial_cmp(b).unwrap()
-                } else {
-                    b.partial_cmp(a).unwrap()
-                }
-            } else if a.is_boolean() && b.is_boolean() {
-                let a = a.as_bool().unwrap();
-                let b = b.as_bool().unwrap();
-                if order == "asc" {
-                    a.cmp(&b)
-                } else {
-                    b.cmp(&a)
-                }
-            } else {
-                Ordering::Equal
-            }
-        });
-    }
-    //3、page or slice
+    //1、page or slice
     let (start, end) = if let Some(paginate) = paginate {
         (
             (if paginate.page.unwrap_or(1) > 0 {
                 paginate.page.unwrap_or(1) - 1
             } else {
                 0
-            }) * paginate.size.unwrap_or(DEFAULT_PAGE_SIZE),
+            }) * paginate.size.unwrap_or(page_size),
             (if paginate.page.unwrap_or(1) > 0 {
                 paginate.page.unwrap_or(1) - 1
             } else {
                 0
-            }) * paginate.size.unwrap_or(DEFAULT_PAGE_SIZE)
-                + paginate.size.unwrap_or(DEFAULT_PAGE_SIZE),
+            }) * paginate.size.unwrap_or(page_size)
+                + paginate.size.unwrap_or(page_size),
         )
     } else if let Some(slice) = slice {
         if let Some(end) = slice.end {
             (slice.start, end)
         } else {
-            (
-                slice.start,
-                slice.start + slice.limit.unwrap_or(DEFAULT_PAGE_SIZE),
-            )
+            (slice.start, slice.start + slice.limit.unwrap_or(page_size))
         }
     } else {
-        (0, DEFAULT_PAGE_SIZE)
+        (0, page_size)
     };
 
-    let body = if start >= values.len() {
-        json!(Vec::<Value>::new()).to_string()
-    } else if end > values.len() {
-        json!(values[start..].to_vec()).to_string()
-    } else {
-        json!(values[start..end].to_vec()).to_string()
+    //2、filter
+    let predicate = match Predicate::parse(&params) {
+        Ok(predicate) => predicate,
+        Err(e) => return invalid(e),
     };
+    let lookups = predicate.lookups();
+    let filtered = predicate != Predicate::All(Vec::new());
+    let fields = fields.map(|fields| {
+        fields
+            .fields
+            .split(',')
+            .filter(|field| !field.is_empty())
+            .map(|field| field.to_string())
+            .collect::<Vec<String>>()
+    });
+    if fields.as_ref().is_some_and(|fields| fields.is_empty()) {
+        return invalid(FilterError::new("_fields", "value must list fields"));
+    }
+    //3、sort
+    let sorted_by = match (sorts.as_slice(), orders.as_slice()) {
+        ([sort], [order]) => Some((sort.clone(), order == "asc")),
+        _ => None,
+    };
+    let order = match (sorts.is_empty(), predicate.search().cloned()) {
+        (false, _) => Some(Order::By(Box::new(move |a, b| {
+            sorts
+                .iter()
+                .zip(orders.iter())
+                .map(|(sort, order)| compare_by(a, b, sort, order))
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or(Ordering::Equal)
+        }))),
+        // the most relevant items first when searching without sorting
+        (true, Some(search)) => Some(Order::Rank(Box::new(move |item| search.score(item)))),
+        (true, None) => None,
+    };
+    // filtering, sorting and serializing run on the query pool, the filter gives up once timed out
+    let store = app_state.store.clone();
+    let runtime = tokio::runtime::Handle::current();
+    let result = app_state
+        .queries
+        .run(move |cancelled| {
+            let query = store::Query {
+                filter: Box::new(move |item| {
+                    !cancelled.load(atomic::Ordering::Relaxed) && predicate.matches(item)
+                }),
+                lookups,
+                filtered,
+                order,
+                sorted_by,
+                range: start..end,
+            };
+            runtime.block_on(store.query(&name, query)).map(|page| {
+                page.map(|page| {
+                    let items = match &fields {
+                        Some(fields) => page
+                            .items
+                            .iter()
+                            .map(|item| path::project(item, fields))
+                            .collect(),
+                        None => page.items,
+                    };
+                    (
+                        page.total,
+                        serde_json::to_string(&items).expect("failed to render response"),
+                    )
+                })
+            })
+        })
+        .await
+        .and_then(|result| result);
+    match result {
+        Ok(Some((total, body))) => Response::builder()
+            .status(StatusCode::OK)
+            .header("X-Total-Count", total.to_string())
+            .header("Content-Type", "application/json")
+            .body(body)
+            .expect("failed to render response"),
+        Ok(None) => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body("not found".into())
+            .expect("failed to render response"),
+        Err((status, message)) => Response::builder()
+            .status(status)
+            .body(message)
+            .expect("failed to render response"),
+    }
+}
 
+/// 400 response naming the parameter of a list request that is not valid
+fn invalid(e: FilterError) -> Response<String> {
     Response::builder()
-        .status(StatusCode::OK)
-        .header("X-Total-Count", values.len().to_string())
+        .status(StatusCode::BAD_REQUEST)
         .header("Content-Type", "application/json")
-        .body(body)
+        .body(e.to_json().to_string())
         .expect("failed to render response")
 }
 
+/// Orders `a` and `b` by the value at path `sort`, items without it come first in `asc` order
+pub fn compare_by(a: &Value, b: &Value, sort: &str, order: &str) -> Ordering {
+    let a = path::get(a, sort);
+    let b = path::get(b, sort);
+    if a.is_none() && b.is_none() {
+        return Ordering::Equal;
+    } else if a.is_none() && b.is_some() {
+        if order == "asc" {
+            return Ordering::Less;
+        } else {
+            return Ordering::Greater;
+        }
+    } else if a.is_some() && b.is_none() {
+        if order == "asc" {
+            return Ordering::Greater;
+        } else {
+            return Ordering::Less;
+        }
+    }
+    let a = a.unwrap();
+    let b = b.unwrap();
+    if a.is_number() && b.is_number() {
+        let a = a.as_f64().unwrap();
+        let b = b.as_f64().unwrap();
+        if order == "asc" {
+            a.partial_cmp(&b).unwrap()
+        } else {
+            b.partial_cmp(&a).unwrap()
+        }
+    } else if a.is_string() && b.is_string() {
+        let a = a.as_str().unwrap();
+        let b = b.as_str().unwrap();
+        if order == "asc" {
+            a.partial_cmp(b).unwrap()
+        } else {
+            b.partial_cmp(a).unwrap()
+        }
+    } else if a.is_boolean() && b.is_boolean() {
+        let a = a.as_bool().unwrap();
+        let b = b.as_bool().unwrap();
+        if order == "asc" {
+            a.cmp(&b)
+        } else {
+            b.cmp(&a)
+        }
+    } else {
+        Ordering::Equal
+    }
+}
+
 pub async fn get_item_by_id(
-    uri: Uri,
-    Path(id): Path<u64>,
+    Path((name, id)): Path<(String, String)>,
     State(app_state): State<AppState>,
 ) -> Result<Json<Value>, (StatusCode, String)> {
-    let name = get_name(uri);
-    match app_state
-        .db_value
-        .read()
-        .await
-        .get(&name)
-        .unwrap()
-        .as_array()
-        .unwrap()
-        .par_iter()
-        .find_any(|item| item[&app_state.id] == id)
-    {
-        Some(item) => Ok(item.clone().into()),
+    match app_state.store.get(&name, &id).await? {
+        Some(item) => Ok(item.into()),
         None => Err((StatusCode::NOT_FOUND, "not found".to_string())),
     }
 }
 
 pub async fn post_item(
-    uri: Uri,
+    Path(name): Path<String>,
     State(app_state): State<AppState>,
     Json(value): Json<Value>,
 ) -> Result<Json<Value>, (StatusCode, String)> {
-    let name = get_name(uri);
     if !value.is_object() {
         return Err((StatusCode::BAD_REQUEST, "value is not object".to_string()));
     }
-    if let Some(id_value) = value.get(&app_state.id) {
-        if !id_value.is_number() || id_value.as_u64().is_none() {
+    let collection = app_state.store.collection(&name).await?;
+    if collection.read_only {
+        return Err(read_only());
+    }
+    if let Some(id_value) = value.get(&collection.id) {
+        if !id::is_valid(id_value) {
             return Err((
                 StatusCode::BAD_REQUEST,
-                "id must be an unsigned integer".to_string(),
+                "id must be an unsigned integer or a string".to_string(),
             ));
         }
     }
-    if let Some(db_value) = app_state.db_value.write().await.as_object_mut() {
-        let old_value = db_value.get_mut(&name).unwrap();
-        if !old_value.is_array() {
-            return Err((StatusCode::BAD_REQUEST, "key is not array".to_string()));
-        }
-        let value = match value.get(&app_state.id) {
-            Some(id) => {
-                //check id
-                let id_exists = old_value.as_array().unwrap().par_iter().any(|item| {
-                    item.get(&app_state.id).unwrap().as_u64().unwrap() == id.as_u64().unwrap()
-                });
-                if id_exists {
-                    return Err((StatusCode::BAD_REQUEST, "id exists".to_string()));
-                }
-                value
-            }
-            None => {
-                //gen id
-                let max_id = old_value
-                    .as_array()
-                    .unwrap()
-                    .par_iter()
-                    .map(|item| item.get(&app_state.id).unwrap().as_u64().unwrap())
-                    .max()
-                    .unwrap_or(0);
-                let mut value_clone = value.clone();
-                let value_with_id = value_clone.as_object_mut().unwrap();
-                value_with_id.insert(app_state.id.clone(), (max_id + 1).into());
-                Value::Object(value_with_id.clone())
-            }
-        };
-        let mut dirty = app_state.dirty.write().await;
-        old_value.as_array_mut().unwrap().push(value.clone());
-        *dirty = true;
-        drop(dirty);
-        Ok(value.into())
-    } else {
-        Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "unknown error".to_string(),
-        ))
-    }
+    app_state.store.insert(&name, value).await.map(Json)
 }
 
 pub async fn update_item_by_id(
-    uri: Uri,
-    Path(id): Path<u64>,
+    Path((name, id)): Path<(String, String)>,
     State(app_state): State<AppState>,
+    headers: HeaderMap,
     Json(value): Json<Value>,
 ) -> Result<Json<Value>, (StatusCode, String)> {
-    let name = get_name(uri);
-    if !value.is_object() {
+    let is_json_patch = is_json_patch(&headers);
+    if !is_json_patch && !value.is_object() {
         return Err((StatusCode::BAD_REQUEST, "value is not object".to_string()));
     }
-    if let Some(db_value) = app_state.db_value.write().await.as_object_mut() {
-        let old_value = db_value.get_mut(&name).unwrap();
-        if !old_value.is_array() {
-            return Err((StatusCode::BAD_REQUEST, "key is not array".to_string()));
-        }
-        let mut value_clone = value.clone();
-        let value_replace_id = value_clone.as_object_mut().unwrap();
-        value_replace_id.insert(app_state.id.clone(), id.into());
-        let arr = old_value.as_array_mut().unwrap().iter_mut();
-        for item in arr {
-            if item[&app_state.id] == id {
-                let mut dirty = app_state.dirty.write().await;
-                *item = value_clone.clone();
-                *dirty = true;
-                drop(dirty);
-                break;
-            }
-        }
-        Ok(value_clone.into())
-    } else {
-        Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "unknown error".to_string(),
-        ))
+    if app_state.store.collection(&name).await?.read_only {
+        return Err(read_only());
     }
+    app_state
+        .store
+        .replace(
+            &name,
+            &id,
+            Box::new(move |item| {
+                if is_json_patch {
+                    json_patch(item, value)
+                } else {
+                    Ok(value)
+                }
+            }),
+        )
+        .await
+        .map(Json)
+}
+
+pub async fn patch_item_by_id(
+    Path((name, id)): Path<(String, String)>,
+    State(app_state): State<AppState>,
+    headers: HeaderMap,
+    Json(value): Json<Value>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let is_json_patch = is_json_patch(&headers);
+    if !is_json_patch && !value.is_object() {
+        return Err((StatusCode::BAD_REQUEST, "value is not object".to_string()));
+    }
+    if app_state.store.collection(&name).await?.read_only {
+        return Err(read_only());
+    }
+    app_state
+        .store
+        .replace(
+            &name,
+            &id,
+            Box::new(move |item| {
+                if is_json_patch {
+                    json_patch(item, value)
+                } else {
+                    let mut patched = item.clone();
+                    merge_patch(&mut patched, &value);
+                    Ok(patched)
+                }
+            }),
+        )
+        .await
+        .map(Json)
 }
 
 pub async fn delete_item_by_id(
-    uri: Uri,
-    Path(id): Path<u64>,
+    Path((name, id)): Path<(String, String)>,
     State(app_state): State<AppState>,
 ) -> Result<Json<Value>, (StatusCode, String)> {
-    let name = get_name(uri);
-    if let Some(db_value) = app_state.db_value.write().await.as_object_mut() {
-        let old_value = db_value.get_mut(&name).unwrap();
-        if !old_value.is_array() {
-            return Err((StatusCode::BAD_REQUEST, "key is not array".to_string()));
-        }
-        match old_value
-            .as_array()
-            .unwrap()
-            .iter()
-            .position(|item| item[&app_state.id] == id)
-        {
-            Some(index) => {
-                let mut dirty = app_state.dirty.write().await;
-                let value = old_value.as_array_mut().unwrap().remove(index);
-                *dirty = true;
-                drop(dirty);
-                Ok(value.into())
-            }
-            None => Err((StatusCode::NOT_FOUND, "not found".to_string())),
-        }
-    } else {
-        Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "unknown error".to_string(),
-        ))
+    if app_state.store.collection(&name).await?.read_only {
+        return Err(read_only());
     }
+    app_state.store.delete(&name, &id).await.map(Json)
+}
+
+fn read_only() -> (StatusCode, String) {
+    (
+        StatusCode::METHOD_NOT_ALLOWED,
+        "collection is read only".to_string(),
+    )
 }
 
 #[derive(Deserialize, Clone)]
@@ -481,6 +384,13 @@ pub struct Sort {
     pub order: String,
 }
 
+/// Paths of the values kept in each listed item, separated by commas
+#[derive(Deserialize)]
+pub struct Fields {
+    #[serde(rename = "_fields")]
+    pub fields: String,
+}
+
 #[derive(Deserialize, Clone)]
 pub struct Slice {
     #[serde(rename = "_start")]
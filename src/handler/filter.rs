@@ -0,0 +1,1021 @@
+use std::ops::Bound;
+
+use regex::{Regex, RegexBuilder};
+use serde_json::{json, Map, Value};
+
+use super::path;
+use crate::store::Lookup;
+
+/// Parameter searching the string values of items for words
+const SEARCH: &str = "q";
+
+/// Parameter limiting `q` to the values at some paths, separated by commas
+const SEARCH_FIELDS: &str = "_q_fields";
+
+/// Parameter holding a json filter document such as `{"$or": [{"a": 1}, {"b": {"$gt": 2}}]}`
+const WHERE: &str = "_where";
+
+/// Which items of a collection a list request selects, parsed once from its parameters
+#[derive(Debug, Clone, PartialEq)]
+pub enum Predicate {
+    /// Every predicate holds, all items when empty
+    All(Vec<Predicate>),
+    /// One of the predicates holds, those of a parameter given several times
+    Any(Vec<Predicate>),
+    /// The predicate does not hold
+    Not(Box<Predicate>),
+    /// `q`, the item holds every word
+    Search(Search),
+    /// `op` holds for the value at path `field`, such as `author.name`
+    Field { field: String, op: Op },
+}
+
+/// A condition on the value of a field, named by the suffix of the parameter
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    /// no suffix
+    Eq(Operand),
+    /// `_ne`
+    Ne(Operand),
+    /// `_lt`
+    Lt(f64),
+    /// `_lte`
+    Lte(f64),
+    /// `_gt`
+    Gt(f64),
+    /// `_gte`
+    Gte(f64),
+    /// `_like`, a string containing the text
+    Like(String),
+    /// `_nlike`, a string not containing the text
+    NLike(String),
+    /// `_contains`, an array with an element equal to the operand
+    Contains(Operand),
+    /// `_ncontains`, an array without any element equal to the operand
+    NContains(Operand),
+    /// `_in`, equal to one of the operands separated by commas
+    In(Vec<Operand>),
+    /// `_nin`, equal to none of the operands separated by commas
+    Nin(Vec<Operand>),
+    /// `_exists` when true, `_nexists` when false
+    Exists(bool),
+    /// `$regex` of `_where`, a string matching the pattern
+    Regex(Pattern),
+}
+
+/// A value compared with the values of fields
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    /// A value of a query string, equal to the same string, or to the number or boolean it spells
+    Text {
+        text: String,
+        number: Option<f64>,
+        boolean: Option<bool>,
+    },
+    /// A value of `_where`, equal to the same json value
+    Json(Value),
+}
+
+/// Words searched for in the strings of items, ignoring case
+#[derive(Debug, Clone, PartialEq)]
+pub struct Search {
+    words: Vec<String>,
+    /// Paths of the values searched, the whole item when `None`
+    fields: Option<Vec<String>>,
+}
+
+/// A compiled regular expression, equal to the same pattern
+#[derive(Debug, Clone)]
+pub struct Pattern(Regex);
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Pattern) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+/// Why the parameter of a list request is not a filter
+#[derive(Debug, PartialEq)]
+pub struct FilterError {
+    pub parameter: String,
+    pub message: String,
+}
+
+impl FilterError {
+    pub fn new(parameter: &str, message: &str) -> FilterError {
+        FilterError {
+            parameter: parameter.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    /// Body of the 400 response
+    pub fn to_json(&self) -> Value {
+        json!({ "error": self.message, "parameter": self.parameter })
+    }
+}
+
+impl Operand {
+    pub fn new(text: &str) -> Operand {
+        Operand::Text {
+            text: text.to_string(),
+            // json numbers are finite
+            number: text.parse::<f64>().ok().filter(|n| n.is_finite()),
+            boolean: text.parse::<bool>().ok(),
+        }
+    }
+
+    pub fn matches(&self, value: &Value) -> bool {
+        match (self, value) {
+            (Operand::Text { text, .. }, Value::String(s)) => s == text,
+            (Operand::Text { number, .. }, Value::Number(n)) => {
+                number.is_some() && n.as_f64() == *number
+            }
+            (Operand::Text { boolean, .. }, Value::Bool(b)) => *boolean == Some(*b),
+            (Operand::Text { .. }, _) => false,
+            // 1 and 1.0 are the same number
+            (Operand::Json(Value::Number(a)), Value::Number(b)) => a.as_f64() == b.as_f64(),
+            (Operand::Json(operand), value) => operand == value,
+        }
+    }
+
+    /// The operand as an index looks it up, `None` for values indexes do not hold
+    fn lookup(&self) -> Option<String> {
+        match self {
+            Operand::Text { text, .. } => Some(text.clone()),
+            Operand::Json(Value::String(s)) => Some(s.clone()),
+            Operand::Json(Value::Number(n)) => Some(n.to_string()),
+            Operand::Json(Value::Bool(b)) => Some(b.to_string()),
+            Operand::Json(_) => None,
+        }
+    }
+}
+
+impl Op {
+    /// Parses the operator named `suffix` with `value`, `None` when there is no such operator
+    fn parse(suffix: &str, value: &str) -> Option<Result<Op, &'static str>> {
+        let number = || match value.parse::<f64>() {
+            Ok(n) if n.is_finite() => Ok(n),
+            _ => Err("value must be a number"),
+        };
+        let exists = |exists: bool| match value {
+            "" | "true" => Ok(Op::Exists(exists)),
+            "false" => Ok(Op::Exists(!exists)),
+            _ => Err("value must be true or false"),
+        };
+        let operands = || value.split(',').map(Operand::new).collect();
+        Some(match suffix {
+            "ne" => Ok(Op::Ne(Operand::new(value))),
+            "lt" => number().map(Op::Lt),
+            "lte" => number().map(Op::Lte),
+            "gt" => number().map(Op::Gt),
+            "gte" => number().map(Op::Gte),
+            "like" => Ok(Op::Like(value.to_string())),
+            "nlike" => Ok(Op::NLike(value.to_string())),
+            "contains" => Ok(Op::Contains(Operand::new(value))),
+            "ncontains" => Ok(Op::NContains(Operand::new(value))),
+            "in" => Ok(Op::In(operands())),
+            "nin" => Ok(Op::Nin(operands())),
+            "exists" => exists(true),
+            "nexists" => exists(false),
+            _ => return None,
+        })
+    }
+
+    /// Whether the op holds for `value`, `None` when the field is missing
+    pub fn matches(&self, value: Option<&Value>) -> bool {
+        let Some(value) = value else {
+            return *self == Op::Exists(false);
+        };
+        let number = value.as_f64();
+        match self {
+            Op::Eq(operand) => operand.matches(value),
+            Op::Ne(operand) => !operand.matches(value),
+            Op::Lt(n) => number.is_some_and(|number| number < *n),
+            Op::Lte(n) => number.is_some_and(|number| number <= *n),
+            Op::Gt(n) => number.is_some_and(|number| number > *n),
+            Op::Gte(n) => number.is_some_and(|number| number >= *n),
+            Op::Like(text) => value.as_str().is_some_and(|s| s.contains(text.as_str())),
+            Op::NLike(text) => value.as_str().is_some_and(|s| !s.contains(text.as_str())),
+            Op::Contains(operand) => value
+                .as_array()
+                .is_some_and(|items| items.iter().any(|item| operand.matches(item))),
+            Op::NContains(operand) => value
+                .as_array()
+                .is_some_and(|items| !items.iter().any(|item| operand.matches(item))),
+            Op::In(operands) => operands.iter().any(|operand| operand.matches(value)),
+            Op::Nin(operands) => !operands.iter().any(|operand| operand.matches(value)),
+            Op::Exists(exists) => *exists,
+            Op::Regex(pattern) => value.as_str().is_some_and(|s| pattern.0.is_match(s)),
+        }
+    }
+}
+
+impl Predicate {
+    /// Parses the filters among the parameters of a list request, `key=value` compares field `key`
+    /// and `key_op=value` applies operator `op` to field `key`, an unknown `op` is an error.
+    /// Other parameters starting with `_` than `_where` are not filters, such as paging,
+    /// sorting or the `_` of cache busting, and are left to others.
+    /// The filters of a key given several times are alternatives.
+    pub fn parse(params: &[(String, String)]) -> Result<Predicate, FilterError> {
+        let search_fields = params
+            .iter()
+            .find(|(key, _)| key == SEARCH_FIELDS)
+            .map(|(key, value)| match Search::fields(value) {
+                Some(fields) if params.iter().any(|(key, _)| key == SEARCH) => Ok(fields),
+                Some(_) => Err(FilterError::new(key, "q is missing")),
+                None => Err(FilterError::new(key, "value must list fields")),
+            })
+            .transpose()?;
+        let mut keys: Vec<(&String, Vec<Predicate>)> = Vec::new();
+        for (key, value) in params {
+            if key.starts_with('_') && key != WHERE {
+                continue;
+            }
+            let predicate = if key == WHERE {
+                serde_json::from_str::<Value>(value)
+                    .map_err(|e| format!("invalid json: {}", e))
+                    .and_then(|document| Predicate::document(&document))
+                    .map_err(|message| FilterError::new(key, &message))?
+            } else if key == SEARCH {
+                match Search::new(value, search_fields.clone()) {
+                    Some(search) => Predicate::Search(search),
+                    // nothing to search for
+                    None => continue,
+                }
+            } else {
+                // a `_` inside a segment of the path other than the last one is part of a field name
+                let split = key
+                    .rsplit_once('_')
+                    .filter(|(field, suffix)| !field.is_empty() && !suffix.contains('.'));
+                match split {
+                    Some((field, suffix)) => Predicate::Field {
+                        field: field.to_string(),
+                        op: Op::parse(suffix, value)
+                            .unwrap_or(Err("unknown operator, filter fields with _ in their name through _where"))
+                            .map_err(|message| FilterError::new(key, message))?,
+                    },
+                    None => Predicate::Field {
+                        field: key.to_string(),
+                        op: Op::Eq(Operand::new(value)),
+                    },
+                }
+            };
+            match keys.iter_mut().find(|(other, _)| *other == key) {
+                Some((_, predicates)) => predicates.push(predicate),
+                None => keys.push((key, vec![predicate])),
+            }
+        }
+        Ok(Predicate::All(
+            keys.into_iter()
+                .map(|(_, mut predicates)| match predicates.len() {
+                    1 => predicates.remove(0),
+                    _ => Predicate::Any(predicates),
+                })
+                .collect(),
+        ))
+    }
+
+    pub fn matches(&self, item: &Value) -> bool {
+        match self {
+            Predicate::All(predicates) => predicates.iter().all(|p| p.matches(item)),
+            Predicate::Any(predicates) => predicates.iter().any(|p| p.matches(item)),
+            Predicate::Not(predicate) => !predicate.matches(item),
+            Predicate::Search(search) => search.score(item) > 0,
+            Predicate::Field { field, op } => op.matches(path::get(item, field)),
+        }
+    }
+
+    /// The search of `q` ranking the items, the first one when `q` is given several times
+    pub fn search(&self) -> Option<&Search> {
+        match self {
+            Predicate::All(predicates) => predicates.iter().find_map(Predicate::search),
+            Predicate::Any(predicates) => predicates.iter().find_map(Predicate::search),
+            Predicate::Search(search) => Some(search),
+            _ => None,
+        }
+    }
+
+    /// The conditions an index may answer, every item matching holds them all
+    pub fn lookups(&self) -> Vec<Lookup> {
+        match self {
+            Predicate::All(predicates) => predicates.iter().flat_map(Predicate::lookups).collect(),
+            // alternatives are answered when each of them is one value of the same field
+            Predicate::Any(predicates) => {
+                let mut any: Option<(String, Vec<String>)> = None;
+                for predicate in predicates {
+                    let mut lookups = predicate.lookups();
+                    let (Some(Lookup::Equal { field, values }), true) =
+                        (lookups.pop(), lookups.is_empty())
+                    else {
+                        return Vec::new();
+                    };
+                    match &mut any {
+                        Some((any_field, any_values)) if *any_field == field => {
+                            any_values.extend(values)
+                        }
+                        Some(_) => return Vec::new(),
+                        None => any = Some((field, values)),
+                    }
+                }
+                any.map(|(field, values)| Lookup::Equal { field, values })
+                    .into_iter()
+                    .collect()
+            }
+            Predicate::Not(_) | Predicate::Search(_) => Vec::new(),
+            Predicate::Field { field, op } => {
+                let range = |lower, upper| Lookup::Range {
+                    field: field.clone(),
+                    lower,
+                    upper,
+                };
+                match op {
+                    Op::Eq(operand) => operand
+                        .lookup()
+                        .map(|value| Lookup::Equal {
+                            field: field.clone(),
+                            values: vec![value],
+                        })
+                        .into_iter()
+                        .collect(),
+                    Op::In(operands) => operands
+                        .iter()
+                        .map(Operand::lookup)
+                        .collect::<Option<Vec<String>>>()
+                        .map(|values| Lookup::Equal {
+                            field: field.clone(),
+                            values,
+                        })
+                        .into_iter()
+                        .collect(),
+                    Op::Lt(n) => vec![range(Bound::Unbounded, Bound::Excluded(*n))],
+                    Op::Lte(n) => vec![range(Bound::Unbounded, Bound::Included(*n))],
+                    Op::Gt(n) => vec![range(Bound::Excluded(*n), Bound::Unbounded)],
+                    Op::Gte(n) => vec![range(Bound::Included(*n), Bound::Unbounded)],
+                    _ => Vec::new(),
+                }
+            }
+        }
+    }
+}
+
+impl Search {
+    /// Searches for the words of `text`, `None` when it has none
+    fn new(text: &str, fields: Option<Vec<String>>) -> Option<Search> {
+        let words = text
+            .split_whitespace()
+            .map(|word| word.to_lowercase())
+            .collect::<Vec<String>>();
+        (!words.is_empty()).then_some(Search { words, fields })
+    }
+
+    fn fields(value: &str) -> Option<Vec<String>> {
+        let fields = value
+            .split(',')
+            .filter(|field| !field.is_empty())
+            .map(|field| field.to_string())
+            .collect::<Vec<String>>();
+        (!fields.is_empty()).then_some(fields)
+    }
+
+    /// How often the words occur in the searched strings of `item`, 0 unless they all do
+    pub fn score(&self, item: &Value) -> usize {
+        let mut counts = vec![0; self.words.len()];
+        match &self.fields {
+            Some(fields) => fields
+                .iter()
+                .filter_map(|field| path::get(item, field))
+                .for_each(|value| self.count(value, &mut counts)),
+            None => self.count(item, &mut counts),
+        }
+        if counts.contains(&0) {
+            0
+        } else {
+            counts.iter().sum()
+        }
+    }
+
+    /// Adds the occurrences of each word in the strings nested in `value` to `counts`
+    fn count(&self, value: &Value, counts: &mut [usize]) {
+        match value {
+            Value::String(s) => {
+                let s = s.to_lowercase();
+                for (word, count) in self.words.iter().zip(counts.iter_mut()) {
+                    *count += s.matches(word.as_str()).count();
+                }
+            }
+            Value::Array(items) => items.iter().for_each(|item| self.count(item, counts)),
+            Value::Object(object) => object.values().for_each(|item| self.count(item, counts)),
+            _ => {}
+        }
+    }
+}
+
+/// Parsing of `_where` documents, written like mongodb queries
+impl Predicate {
+    /// A document holds `field: value` for equality, `field: {"$op": operand, ...}`,
+    /// `$and` and `$or` over lists of documents, and `$not` of a document
+    fn document(document: &Value) -> Result<Predicate, String> {
+        let document = document
+            .as_object()
+            .ok_or_else(|| format!("{} must be an object", document))?;
+        let mut predicates = Vec::new();
+        for (key, value) in document {
+            predicates.push(match key.as_str() {
+                "$and" => Predicate::All(Predicate::documents(key, value)?),
+                "$or" => Predicate::Any(Predicate::documents(key, value)?),
+                "$not" => Predicate::Not(Box::new(Predicate::document(value)?)),
+                key if key.starts_with('$') => return Err(format!("unknown operator {}", key)),
+                field => Predicate::condition(field, value)?,
+            });
+        }
+        Ok(match predicates.len() {
+            1 => predicates.remove(0),
+            _ => Predicate::All(predicates),
+        })
+    }
+
+    fn documents(key: &str, value: &Value) -> Result<Vec<Predicate>, String> {
+        match value.as_array() {
+            Some(documents) if !documents.is_empty() => {
+                documents.iter().map(Predicate::document).collect()
+            }
+            _ => Err(format!("{} needs a list of documents", key)),
+        }
+    }
+
+    /// Condition on `field`, an object whose keys are all operators applies them, other values are compared
+    fn condition(field: &str, value: &Value) -> Result<Predicate, String> {
+        let operators = match value {
+            Value::Object(operators)
+                if !operators.is_empty() && operators.keys().all(|key| key.starts_with('$')) =>
+            {
+                operators
+            }
+            value => {
+                return Ok(Predicate::Field {
+                    field: field.to_string(),
+                    op: Op::Eq(Operand::Json(value.clone())),
+                })
+            }
+        };
+        let mut predicates = Vec::new();
+        for (key, operand) in operators {
+            let number = || {
+                operand
+                    .as_f64()
+                    .ok_or_else(|| format!("{} needs a number", key))
+            };
+            let operands = || match operand.as_array() {
+                Some(operands) => Ok(operands.iter().cloned().map(Operand::Json).collect()),
+                None => Err(format!("{} needs a list", key)),
+            };
+            let op = match key.as_str() {
+                "$eq" => Op::Eq(Operand::Json(operand.clone())),
+                "$ne" => Op::Ne(Operand::Json(operand.clone())),
+                "$gt" => Op::Gt(number()?),
+                "$gte" => Op::Gte(number()?),
+                "$lt" => Op::Lt(number()?),
+                "$lte" => Op::Lte(number()?),
+                "$in" => Op::In(operands()?),
+                "$nin" => Op::Nin(operands()?),
+                "$exists" => Op::Exists(
+                    operand
+                        .as_bool()
+                        .ok_or_else(|| format!("{} needs true or false", key))?,
+                ),
+                "$regex" => Op::Regex(Pattern::new(operand, operators)?),
+                "$options" if operators.contains_key("$regex") => continue,
+                "$not" => {
+                    predicates.push(Predicate::Not(Box::new(Predicate::condition(
+                        field,
+                        &not_operand(operand),
+                    )?)));
+                    continue;
+                }
+                key => return Err(format!("unknown operator {}", key)),
+            };
+            predicates.push(Predicate::Field {
+                field: field.to_string(),
+                op,
+            });
+        }
+        Ok(match predicates.len() {
+            1 => predicates.remove(0),
+            _ => Predicate::All(predicates),
+        })
+    }
+}
+
+/// `$not` takes operators, or a pattern as a string
+fn not_operand(operand: &Value) -> Value {
+    match operand {
+        Value::String(pattern) => {
+            let mut operators = Map::new();
+            operators.insert("$regex".to_string(), Value::String(pattern.clone()));
+            Value::Object(operators)
+        }
+        operand => operand.clone(),
+    }
+}
+
+impl Pattern {
+    /// Compiles `$regex` with the flags of `$options`, `i`, `m`, `s` and `x`
+    fn new(pattern: &Value, operators: &Map<String, Value>) -> Result<Pattern, String> {
+        let pattern = pattern.as_str().ok_or("$regex needs a string")?;
+        let mut builder = RegexBuilder::new(pattern);
+        let options = match operators.get("$options") {
+            Some(Value::String(options)) => options.as_str(),
+            Some(_) => return Err("$options needs a string".to_string()),
+            None => "",
+        };
+        for option in options.chars() {
+            match option {
+                'i' => builder.case_insensitive(true),
+                'm' => builder.multi_line(true),
+                's' => builder.dot_matches_new_line(true),
+                'x' => builder.ignore_whitespace(true),
+                _ => return Err(format!("unknown $options {}", option)),
+            };
+        }
+        builder
+            .build()
+            .map(Pattern)
+            .map_err(|e| format!("invalid $regex: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(params: &[(&str, &str)]) -> Result<Predicate, FilterError> {
+        let params = params
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>();
+        Predicate::parse(&params)
+    }
+
+    fn matches(key: &str, value: &str, item: Value) -> bool {
+        parse(&[(key, value)]).unwrap().matches(&item)
+    }
+
+    fn error(key: &str, value: &str) -> String {
+        parse(&[(key, value)]).unwrap_err().message
+    }
+
+    #[test]
+    fn eq() {
+        assert!(matches("title", "a", json!({"title": "a"})));
+        assert!(!matches("title", "b", json!({"title": "a"})));
+        assert!(matches("views", "3", json!({"views": 3})));
+        assert!(matches("views", "3.0", json!({"views": 3})));
+        assert!(!matches("views", "x", json!({"views": 3})));
+        assert!(matches("done", "true", json!({"done": true})));
+        assert!(!matches("done", "maybe", json!({"done": true})));
+        assert!(!matches("tags", "a", json!({"tags": ["a"]})));
+        assert!(!matches("title", "a", json!({})));
+    }
+
+    #[test]
+    fn ne() {
+        assert!(matches("title_ne", "b", json!({"title": "a"})));
+        assert!(!matches("title_ne", "a", json!({"title": "a"})));
+        assert!(!matches("views_ne", "3", json!({"views": 3})));
+        assert!(!matches("done_ne", "false", json!({"done": false})));
+        assert!(matches("done_ne", "maybe", json!({"done": true})));
+        assert!(!matches("title_ne", "a", json!({})));
+    }
+
+    #[test]
+    fn ranges() {
+        let item = json!({"views": 5, "title": "5"});
+        assert!(matches("views_lt", "6", item.clone()));
+        assert!(!matches("views_lt", "5", item.clone()));
+        assert!(matches("views_lte", "5", item.clone()));
+        assert!(!matches("views_lte", "4.5", item.clone()));
+        assert!(matches("views_gt", "4", item.clone()));
+        assert!(!matches("views_gt", "5", item.clone()));
+        assert!(matches("views_gte", "5", item.clone()));
+        assert!(!matches("views_gte", "5.5", item.clone()));
+        assert!(!matches("title_gt", "1", item.clone()));
+        assert!(!matches("missing_lt", "9", item));
+    }
+
+    #[test]
+    fn like() {
+        let item = json!({"title": "jserver", "views": 10});
+        assert!(matches("title_like", "serv", item.clone()));
+        assert!(!matches("title_like", "Serv", item.clone()));
+        assert!(!matches("views_like", "1", item.clone()));
+        assert!(matches("title_nlike", "json", item.clone()));
+        assert!(!matches("title_nlike", "serv", item.clone()));
+        assert!(!matches("views_nlike", "2", item));
+    }
+
+    #[test]
+    fn contains() {
+        let item = json!({"tags": ["a", 1, true], "title": "a"});
+        assert!(matches("tags_contains", "a", item.clone()));
+        assert!(matches("tags_contains", "1", item.clone()));
+        assert!(matches("tags_contains", "true", item.clone()));
+        assert!(!matches("tags_contains", "b", item.clone()));
+        assert!(!matches("title_contains", "a", item.clone()));
+        assert!(matches("tags_ncontains", "b", item.clone()));
+        assert!(!matches("tags_ncontains", "a", item.clone()));
+        assert!(!matches("title_ncontains", "b", item));
+    }
+
+    #[test]
+    fn in_nin() {
+        let item = json!({"id": 5, "status": "open", "done": false});
+        assert!(matches("id_in", "1,5,9", item.clone()));
+        assert!(!matches("id_in", "1,9", item.clone()));
+        assert!(matches("status_in", "open,pending", item.clone()));
+        assert!(matches("done_in", "false", item.clone()));
+        assert!(!matches("done_in", "true,open", item.clone()));
+        assert!(matches("id_nin", "1,9", item.clone()));
+        assert!(!matches("id_nin", "1,5", item.clone()));
+        assert!(matches("status_nin", "closed", item.clone()));
+        assert!(!matches("done_nin", "false,true", item.clone()));
+        assert!(!matches("missing_in", "1", item.clone()));
+        assert!(!matches("missing_nin", "1", item));
+    }
+
+    #[test]
+    fn repeated() {
+        let predicate =
+            parse(&[("status", "open"), ("views_gt", "1"), ("status", "pending")]).unwrap();
+        assert!(predicate.matches(&json!({"status": "open", "views": 2})));
+        assert!(predicate.matches(&json!({"status": "pending", "views": 2})));
+        assert!(!predicate.matches(&json!({"status": "closed", "views": 2})));
+        assert!(!predicate.matches(&json!({"status": "open", "views": 1})));
+        let predicate = parse(&[("views_lt", "2"), ("views_gt", "8")]).unwrap();
+        assert!(!predicate.matches(&json!({"views": 5})));
+        let predicate = parse(&[("views_lt", "2"), ("views_lt", "8")]).unwrap();
+        assert!(predicate.matches(&json!({"views": 5})));
+    }
+
+    #[test]
+    fn exists() {
+        let item = json!({"title": null});
+        assert!(matches("title_exists", "", item.clone()));
+        assert!(matches("title_exists", "true", item.clone()));
+        assert!(!matches("title_exists", "false", item.clone()));
+        assert!(!matches("views_exists", "true", item.clone()));
+        assert!(matches("views_nexists", "", item.clone()));
+        assert!(matches("views_nexists", "true", item.clone()));
+        assert!(!matches("title_nexists", "true", item.clone()));
+        assert!(matches("title_nexists", "false", item));
+    }
+
+    #[test]
+    fn paths() {
+        let item = json!({
+            "author": {"name": "jupiter", "age": 30},
+            "tags": ["a", "b"],
+            "comments": [{"likes": 3, "tags": ["x"]}]
+        });
+        assert!(matches("author.name", "jupiter", item.clone()));
+        assert!(matches("author.name_ne", "mars", item.clone()));
+        assert!(matches("author.age_gte", "30", item.clone()));
+        assert!(matches("author.age_lt", "31", item.clone()));
+        assert!(matches("author.name_like", "pit", item.clone()));
+        assert!(matches("tags.1", "b", item.clone()));
+        assert!(!matches("tags.2", "b", item.clone()));
+        assert!(matches("comments.0.likes_gt", "2", item.clone()));
+        assert!(matches("comments.0.tags_contains", "x", item.clone()));
+        assert!(matches("comments.0.tags_ncontains", "y", item.clone()));
+        assert!(matches("author.age_exists", "", item.clone()));
+        assert!(matches("author.email_nexists", "", item.clone()));
+        assert!(!matches("tags.x_exists", "", item.clone()));
+        assert!(!matches("author.name.first", "jupiter", item));
+    }
+
+    #[test]
+    fn projection() {
+        let item = json!({
+            "id": 1,
+            "title": "a",
+            "author": {"name": "jupiter", "age": 30},
+            "comments": [{"text": "x", "likes": 3}, {"text": "y", "likes": 5}]
+        });
+        let fields = |fields: &[&str]| {
+            let fields = fields.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+            path::project(&item, &fields)
+        };
+        assert_eq!(
+            fields(&["id", "author.name", "missing"]),
+            json!({"id": 1, "author": {"name": "jupiter"}})
+        );
+        assert_eq!(
+            fields(&["comments.1.text", "comments.0.likes", "comments.1.likes"]),
+            json!({"comments": [{"likes": 3}, {"text": "y", "likes": 5}]})
+        );
+        assert_eq!(
+            fields(&["author.name", "author"]),
+            json!({"author": {"name": "jupiter", "age": 30}})
+        );
+    }
+
+    fn matches_where(document: Value, item: &Value) -> bool {
+        matches("_where", &document.to_string(), item.clone())
+    }
+
+    #[test]
+    fn where_fields() {
+        let item = json!({
+            "id": 3,
+            "title": "Hello jserver",
+            "done": false,
+            "author": {"name": "jupiter", "age": 30},
+            "tags": ["a", "b"]
+        });
+        assert!(matches_where(json!({"id": 3}), &item));
+        assert!(matches_where(json!({"id": 3.0}), &item));
+        assert!(!matches_where(json!({"id": "3"}), &item));
+        assert!(matches_where(
+            json!({"done": false, "author.name": "jupiter"}),
+            &item
+        ));
+        assert!(matches_where(json!({"tags": ["a", "b"]}), &item));
+        assert!(matches_where(
+            json!({"author": {"name": "jupiter", "age": 30}}),
+            &item
+        ));
+        assert!(matches_where(json!({"id": {"$eq": 3}}), &item));
+        assert!(matches_where(json!({"id": {"$ne": 4}}), &item));
+        assert!(matches_where(
+            json!({"author.age": {"$gt": 29, "$lte": 30}}),
+            &item
+        ));
+        assert!(!matches_where(json!({"author.age": {"$gte": 31}}), &item));
+        assert!(matches_where(json!({"author.age": {"$lt": 31}}), &item));
+        assert!(matches_where(json!({"id": {"$in": [1, 3]}}), &item));
+        assert!(!matches_where(json!({"id": {"$in": ["3"]}}), &item));
+        assert!(matches_where(
+            json!({"tags.1": {"$nin": ["a", "c"]}}),
+            &item
+        ));
+        assert!(matches_where(
+            json!({"author.email": {"$exists": false}}),
+            &item
+        ));
+        assert!(matches_where(
+            json!({"title": {"$regex": "^hello", "$options": "i"}}),
+            &item
+        ));
+        assert!(!matches_where(
+            json!({"title": {"$regex": "^hello"}}),
+            &item
+        ));
+        assert!(!matches_where(json!({"id": {"$regex": "3"}}), &item));
+        assert!(matches_where(json!({"title": {"$not": "^Bye"}}), &item));
+        assert!(!matches_where(
+            json!({"author.age": {"$not": {"$gt": 20}}}),
+            &item
+        ));
+    }
+
+    #[test]
+    fn where_logic() {
+        let item = json!({"status": "open", "views": 5, "author": {"name": "jupiter"}});
+        assert!(matches_where(
+            json!({"$or": [{"status": "closed"}, {"views": {"$gte": 5}}]}),
+            &item
+        ));
+        assert!(!matches_where(
+            json!({"$or": [{"status": "closed"}, {"views": {"$gt": 5}}]}),
+            &item
+        ));
+        assert!(matches_where(
+            json!({"$and": [{"status": "open"}, {"author.name": {"$in": ["jupiter", "mars"]}}]}),
+            &item
+        ));
+        assert!(matches_where(json!({"$not": {"status": "closed"}}), &item));
+        assert!(!matches_where(
+            json!({"$not": {"$or": [{"status": "closed"}, {"views": 5}]}}),
+            &item
+        ));
+        let predicate = parse(&[
+            ("_where", r#"{"$or": [{"views": 1}, {"views": 5}]}"#),
+            ("status", "open"),
+        ])
+        .unwrap();
+        assert!(predicate.matches(&item));
+        assert!(!predicate.matches(&json!({"status": "closed", "views": 5})));
+    }
+
+    #[test]
+    fn where_errors() {
+        assert!(error("_where", "{").starts_with("invalid json"));
+        assert_eq!(error("_where", "[]"), "[] must be an object");
+        assert_eq!(error("_where", r#"{"$nor": []}"#), "unknown operator $nor");
+        assert_eq!(
+            error("_where", r#"{"a": {"$gt": 1, "$foo": 2}}"#),
+            "unknown operator $foo"
+        );
+        assert_eq!(
+            error("_where", r#"{"$or": []}"#),
+            "$or needs a list of documents"
+        );
+        assert_eq!(
+            error("_where", r#"{"$and": {}}"#),
+            "$and needs a list of documents"
+        );
+        assert_eq!(
+            error("_where", r#"{"a": {"$gt": "1"}}"#),
+            "$gt needs a number"
+        );
+        assert_eq!(error("_where", r#"{"a": {"$in": 1}}"#), "$in needs a list");
+        assert_eq!(
+            error("_where", r#"{"a": {"$exists": 1}}"#),
+            "$exists needs true or false"
+        );
+        assert_eq!(
+            error("_where", r#"{"a": {"$regex": 1}}"#),
+            "$regex needs a string"
+        );
+        assert!(error("_where", r#"{"a": {"$regex": "("}}"#).starts_with("invalid $regex"));
+        assert_eq!(
+            error("_where", r#"{"a": {"$regex": "x", "$options": "q"}}"#),
+            "unknown $options q"
+        );
+        assert_eq!(
+            error("_where", r#"{"a": {"$options": "i"}}"#),
+            "unknown operator $options"
+        );
+        assert_eq!(parse(&[("_where", "{")]).unwrap_err().parameter, "_where");
+    }
+
+    #[test]
+    fn search() {
+        let item = json!({
+            "title": "Rust web servers",
+            "body": "A mock server in rust",
+            "views": 12,
+            "tags": ["json", "Mock"],
+            "author": {"name": "Jupiter"}
+        });
+        assert!(matches("q", "rust", item.clone()));
+        assert!(matches("q", "RUST Mock", item.clone()));
+        assert!(matches("q", "jup", item.clone()));
+        assert!(matches("q", "json", item.clone()));
+        assert!(!matches("q", "rust python", item.clone()));
+        assert!(!matches("q", "12", item.clone()));
+        assert!(matches("q", "  ", item.clone()));
+        let scoped = |q: &str, fields: &str| {
+            parse(&[("q", q), ("_q_fields", fields)])
+                .unwrap()
+                .matches(&item)
+        };
+        assert!(scoped("rust", "title,body"));
+        assert!(!scoped("mock", "title"));
+        assert!(scoped("mock", "tags"));
+        assert!(scoped("jupiter", "author.name"));
+        assert!(!scoped("jupiter", "missing"));
+        let predicate = parse(&[("q", "rust"), ("views_gt", "20")]).unwrap();
+        assert!(!predicate.matches(&item));
+    }
+
+    #[test]
+    fn search_score() {
+        let search = |params: &[(&str, &str)]| parse(params).unwrap().search().cloned().unwrap();
+        let item = json!({"title": "Rust, rust", "body": "rust and mock"});
+        assert_eq!(search(&[("q", "rust")]).score(&item), 3);
+        assert_eq!(search(&[("q", "rust mock")]).score(&item), 4);
+        assert_eq!(search(&[("q", "rust python")]).score(&item), 0);
+        assert_eq!(
+            search(&[("q", "rust"), ("_q_fields", "body")]).score(&item),
+            1
+        );
+        assert!(parse(&[("q", "")]).unwrap().search().is_none());
+        assert!(parse(&[("title", "a")]).unwrap().search().is_none());
+    }
+
+    #[test]
+    fn search_errors() {
+        assert_eq!(error("_q_fields", "title"), "q is missing");
+        let error = parse(&[("q", "a"), ("_q_fields", ",")]).unwrap_err();
+        assert_eq!(error.parameter, "_q_fields");
+        assert_eq!(error.message, "value must list fields");
+    }
+
+    #[test]
+    fn all() {
+        let predicate = parse(&[("views_gt", "1"), ("title", "a"), ("_page", "2")]).unwrap();
+        assert!(predicate.matches(&json!({"views": 2, "title": "a"})));
+        assert!(!predicate.matches(&json!({"views": 2, "title": "b"})));
+        assert!(!predicate.matches(&json!({"views": 1, "title": "a"})));
+        assert!(parse(&[]).unwrap().matches(&json!({})));
+        assert_eq!(
+            parse(&[("_", "1700000000"), ("_embed", "comments"), ("_gt", "x")]).unwrap(),
+            Predicate::All(Vec::new())
+        );
+    }
+
+    #[test]
+    fn suffixes() {
+        assert_eq!(
+            parse(&[("views_nlike", "x")]).unwrap(),
+            Predicate::All(vec![Predicate::Field {
+                field: "views".to_string(),
+                op: Op::NLike("x".to_string()),
+            }])
+        );
+        assert_eq!(
+            parse(&[("user_info.name", "x")]).unwrap(),
+            Predicate::All(vec![Predicate::Field {
+                field: "user_info.name".to_string(),
+                op: Op::Eq(Operand::new("x")),
+            }])
+        );
+        assert_eq!(
+            parse(&[("a_b_gte", "1")]).unwrap(),
+            Predicate::All(vec![Predicate::Field {
+                field: "a_b".to_string(),
+                op: Op::Gte(1.0),
+            }])
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("views_gt", "x"), "value must be a number");
+        assert_eq!(error("views_lte", "NaN"), "value must be a number");
+        assert_eq!(error("views_lt", ""), "value must be a number");
+        assert_eq!(error("views_gte", "inf"), "value must be a number");
+        assert_eq!(
+            error("title_exists", "maybe"),
+            "value must be true or false"
+        );
+        assert_eq!(error("title_nexists", "1"), "value must be true or false");
+        let unknown = "unknown operator, filter fields with _ in their name through _where";
+        assert_eq!(error("views_lke", "1"), unknown);
+        assert_eq!(error("user_name", "x"), unknown);
+        assert_eq!(error("author.user_name", "x"), unknown);
+        let error = parse(&[("title", "a"), ("views_gt", "x")]).unwrap_err();
+        assert_eq!(
+            error.to_json(),
+            json!({"error": "value must be a number", "parameter": "views_gt"})
+        );
+    }
+
+    #[test]
+    fn any_lookups() {
+        let values = |params: &[(&str, &str)]| match parse(params).unwrap().lookups().as_slice() {
+            [Lookup::Equal { values, .. }] => Some(values.clone()),
+            [] => None,
+            _ => panic!("one lookup at most"),
+        };
+        assert_eq!(
+            values(&[("id_in", "1,5")]),
+            Some(vec!["1".into(), "5".into()])
+        );
+        assert_eq!(
+            values(&[("s", "a"), ("s", "b")]),
+            Some(vec!["a".into(), "b".into()])
+        );
+        assert_eq!(
+            values(&[("s_in", "a,b"), ("s_in", "c")]),
+            Some(vec!["a".into(), "b".into(), "c".into()])
+        );
+        assert_eq!(values(&[("s_ne", "a"), ("s_ne", "b")]), None);
+        assert_eq!(values(&[("id_nin", "1")]), None);
+    }
+
+    #[test]
+    fn where_lookups() {
+        let lookups = parse(&[(
+            "_where",
+            r#"{"a": 1, "b": {"$in": ["x", true]}, "c": {"$gt": 2}, "d": null, "$or": [{"e": 1}]}"#,
+        )])
+        .unwrap()
+        .lookups();
+        let fields = lookups
+            .iter()
+            .map(|lookup| match lookup {
+                Lookup::Equal { field, values } => format!("{}={}", field, values.join(",")),
+                Lookup::Range { field, .. } => format!("{}>", field),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(fields, ["e=1", "a=1", "b=x,true", "c>"]);
+    }
+
+    #[test]
+    fn lookups() {
+        let lookups = parse(&[("title", "a"), ("views_gt", "1"), ("title_ne", "b")])
+            .unwrap()
+            .lookups();
+        assert_eq!(lookups.len(), 2);
+        assert!(
+            matches!(&lookups[0], Lookup::Equal { field, values } if field == "title" && values == &["a"])
+        );
+        assert!(matches!(
+            &lookups[1],
+            Lookup::Range { field, lower: Bound::Excluded(n), upper: Bound::Unbounded }
+                if field == "views" && *n == 1.0
+        ));
+    }
+}
@@ -0,0 +1,55 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+/// How `post_item` generates the id of an item posted without one
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IdStrategy {
+    /// max + 1 for integer ids, uuid for string ids
+    Auto,
+    /// max + 1
+    Increment,
+    /// uuid v4
+    Uuid,
+    Ulid,
+    Nanoid,
+}
+
+/// Ids are unsigned integers or strings
+pub fn is_valid(id: &Value) -> bool {
+    id.is_u64() || id.is_string()
+}
+
+pub fn same_kind(a: &Value, b: &Value) -> bool {
+    (a.is_u64() && b.is_u64()) || (a.is_string() && b.is_string())
+}
+
+/// The ids of a collection are all of the same kind, so the id of the first item tells
+/// whether they are strings, `max_id` is only called for integer ids
+pub fn generate(
+    strategy: IdStrategy,
+    first_id: Option<&Value>,
+    max_id: impl FnOnce() -> u64,
+) -> Result<Value, String> {
+    let string_ids = first_id.map(|id| id.is_string());
+    let strategy = match strategy {
+        IdStrategy::Auto if string_ids == Some(true) => IdStrategy::Uuid,
+        IdStrategy::Auto => IdStrategy::Increment,
+        strategy => strategy,
+    };
+    if strategy == IdStrategy::Increment {
+        if string_ids == Some(true) {
+            return Err("can not generate an integer id for string ids".to_string());
+        }
+        return Ok((max_id() + 1).into());
+    }
+    if string_ids == Some(false) {
+        return Err("can not generate a string id for integer ids".to_string());
+    }
+    Ok(match strategy {
+        IdStrategy::Ulid => ulid::Ulid::new().to_string(),
+        IdStrategy::Nanoid => nanoid::nanoid!(),
+        _ => uuid::Uuid::new_v4().to_string(),
+    }
+    .into())
+}
@@ -0,0 +1,76 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use super::id::IdStrategy;
+
+/// Reserved top-level key holding the per-collection configuration, it is not served under `/api`
+pub const META_KEY: &str = "_meta";
+
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+
+/// Configuration of one collection in the `_meta` section, unset fields fall back to the command line
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CollectionConfig {
+    pub id: Option<String>,
+    pub id_strategy: Option<IdStrategy>,
+    pub read_only: bool,
+    pub page_size: Option<usize>,
+    pub indexes: BTreeMap<String, IndexKind>,
+}
+
+/// Index of a field used by list requests
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexKind {
+    /// Answers `field=value`
+    Hash,
+    /// Answers `field=value`, `field_gt`, `field_gte`, `field_lt`, `field_lte` and `_sort=field`
+    Ordered,
+}
+
+/// Effective configuration of a collection
+#[derive(Debug, Clone)]
+pub struct Collection {
+    pub id: String,
+    pub id_strategy: IdStrategy,
+    pub read_only: bool,
+    pub page_size: usize,
+    pub indexes: BTreeMap<String, IndexKind>,
+}
+
+pub fn parse_meta(
+    db_value: &Map<String, Value>,
+) -> Result<HashMap<String, CollectionConfig>, String> {
+    match db_value.get(META_KEY) {
+        Some(meta) => serde_json::from_value(meta.clone())
+            .map_err(|e| format!("Invalid [{}] section: {}", META_KEY, e)),
+        None => Ok(HashMap::new()),
+    }
+}
+
+pub fn resolve(config: Option<&CollectionConfig>, id: &str, id_strategy: IdStrategy) -> Collection {
+    let config = config.cloned().unwrap_or_default();
+    Collection {
+        id: config.id.unwrap_or_else(|| id.to_string()),
+        id_strategy: config.id_strategy.unwrap_or(id_strategy),
+        read_only: config.read_only,
+        page_size: config.page_size.unwrap_or(DEFAULT_PAGE_SIZE),
+        indexes: config.indexes,
+    }
+}
+
+/// Looks up the configuration of collection `name` in the `_meta` section, which is validated when loaded
+pub fn collection(
+    meta: Option<&Value>,
+    name: &str,
+    id: &str,
+    id_strategy: IdStrategy,
+) -> Collection {
+    let config = meta
+        .and_then(|meta| meta.get(name))
+        .and_then(|config| serde_json::from_value::<CollectionConfig>(config.clone()).ok());
+    resolve(config.as_ref(), id, id_strategy)
+}
@@ -1,57 +1,64 @@
 use axum::{
-    extract::{DefaultBodyLimit, State},
-    http::Uri,
-    routing::{delete, get, patch, post, put},
-    Json, Router,
+    body::HttpBody,
+    extract::{DefaultBodyLimit, Path, State},
+    handler::Handler,
+    http::{Method, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    BoxError, Json, Router,
 };
-use serde_json::Value;
+use serde_json::{json, Map, Value};
 use tower_http::{
     cors::{Any, CorsLayer},
     limit::RequestBodyLimitLayer,
     services::ServeDir,
 };
 
-use crate::{AppState, Args};
+use crate::{format::Format, persist, persist::Durability, store::Kind, AppState, Args};
 
-mod array;
+pub mod array;
+pub mod filter;
+pub mod id;
+pub mod meta;
+mod patch;
+pub mod path;
+mod seed;
+mod snapshot;
 mod upload;
 mod value;
 
 pub async fn build_router(app_state: AppState, args: Args) -> Router {
-    use rayon::prelude::*;
-    let mut api_routers = Router::new();
-    let db_value = app_state.db_value.read().await;
-    let id = &app_state.id;
-    for (key, value) in db_value.as_object().expect("Invalid json object").iter() {
-        if value.is_array() {
-            let value_id_check = value.as_array().unwrap().par_iter().all(|item| {
-                item.is_object() && item.get(id).is_some() && item.get(id).unwrap().is_u64()
-            });
-            if !value_id_check {
-                log::error!("Array[{}] item object must have field [{}] and its value must be an unsigned integer", key, id);
-                panic!();
-            }
-            api_routers = api_routers.route(&format!("/{}", key), get(array::list));
-            api_routers = api_routers.route(&format!("/{}/:id", key), get(array::get_item_by_id));
-            api_routers = api_routers.route(&format!("/{}", key), post(array::post_item));
-            api_routers =
-                api_routers.route(&format!("/{}/:id", key), put(array::update_item_by_id));
-            api_routers =
-                api_routers.route(&format!("/{}/:id", key), patch(array::update_item_by_id));
-            api_routers =
-                api_routers.route(&format!("/{}/:id", key), delete(array::delete_item_by_id));
-        } else if !value.is_null() {
-            api_routers = api_routers.route(&format!("/{}", key), get(value::get_value));
-            api_routers = api_routers.route(&format!("/{}", key), post(value::update_value));
-            api_routers = api_routers.route(&format!("/{}", key), put(value::update_value));
-            api_routers = api_routers.route(&format!("/{}", key), patch(value::update_value));
-        }
-    }
+    let api_routers = Router::new()
+        .route(
+            "/:name",
+            get(dispatch).post(dispatch).put(dispatch).patch(dispatch),
+        )
+        .route(
+            "/:name/:id",
+            get(array::get_item_by_id)
+                .put(array::update_item_by_id)
+                .patch(array::patch_item_by_id)
+                .delete(array::delete_item_by_id),
+        );
 
     Router::new()
         .route("/db", get(db))
+        .route("/db/status", get(db_status))
+        .route("/db/reset", post(seed::reset))
+        .route("/db/reset/:name", post(seed::reset_collection))
+        .route("/db/snapshots", get(snapshot::list).post(snapshot::create))
+        .route("/db/snapshots/:name/restore", post(snapshot::restore))
         .route("/upload", post(upload::upload))
         .nest("/api", api_routers)
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            persist_changes,
+        ))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            reject_in_read_only,
+        ))
         .fallback_service(ServeDir::new(args.public_path))
         .layer(
             CorsLayer::new()
@@ -66,11 +73,186 @@ pub async fn build_router(app_state: AppState, args: Args) -> Router {
         .with_state(app_state.clone())
 }
 
-async fn db(State(app_state): State<AppState>) -> Json<Value> {
-    let db_value = app_state.db_value.read().await;
-    db_value.clone().into()
+/// Rejects every changing request, uploads included, when running with `--read-only`
+async fn reject_in_read_only<B>(
+    State(app_state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if app_state.read_only && !is_safe(request.method()) {
+        return (
+            StatusCode::METHOD_NOT_ALLOWED,
+            Json(json!({ "error": "database is read only" })),
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+fn is_safe(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
 }
 
-pub fn get_name(uri: Uri) -> String {
-    uri.path().split('/').nth(1).unwrap().to_string()
+/// Rejects changing requests while the database file can not be saved if asked to,
+/// and saves the changes of a request before it is answered when durability is `immediate`
+async fn persist_changes<B>(
+    State(app_state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let is_safe = is_safe(request.method());
+    if !is_safe
+        && app_state.reject_writes_on_save_error
+        && app_state.save_status.read().await.failures > 0
+    {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "database file can not be saved",
+        )
+            .into_response();
+    }
+    let response = next.run(request).await;
+    if !is_safe && app_state.durability == Durability::Immediate {
+        if let Err(e) = persist::save(&app_state).await {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("changed in memory but not saved: {}", e),
+            )
+                .into_response();
+        }
+    }
+    response
+}
+
+async fn db(State(app_state): State<AppState>) -> Result<Json<Value>, (StatusCode, String)> {
+    Ok(Value::Object(app_state.store.export().await?).into())
+}
+
+async fn db_status(State(app_state): State<AppState>) -> Json<Value> {
+    let dirty = app_state.dirty.read().await.is_dirty();
+    let journal_size = match &app_state.journal {
+        Some(journal) => Some(journal.lock().await.size()),
+        None => None,
+    };
+    let save_status = app_state.save_status.read().await.clone();
+    json!({
+        "dirty": dirty,
+        "journal_size": journal_size,
+        "last_saved": save_status.last_saved,
+        "last_error": save_status.last_error,
+        "failures": save_status.failures,
+    })
+    .into()
+}
+
+/// Checks that every top-level array is a collection of objects carrying an id,
+/// either unsigned integers or strings in the same array, and that the `_meta` section is valid
+pub fn check_db(db_value: &Value, id: &str) -> Result<(), String> {
+    use rayon::prelude::*;
+    let db_object = db_value
+        .as_object()
+        .ok_or_else(|| "Database must be a json object".to_string())?;
+    let meta = meta::parse_meta(db_object)?;
+    for (key, config) in meta.iter() {
+        if config.page_size == Some(0) {
+            return Err(format!("Array[{}] page_size can not be zero", key));
+        }
+    }
+    for (key, value) in db_object.iter() {
+        let id = meta
+            .get(key)
+            .and_then(|config| config.id.as_deref())
+            .unwrap_or(id);
+        if let Some(items) = value.as_array() {
+            let error = || {
+                Err(format!(
+                    "Array[{}] item object must have field [{}] and its values must be all unsigned integers or all strings",
+                    key, id
+                ))
+            };
+            if items.is_empty() {
+                continue;
+            }
+            // the other ids are compared with the first one, which must be valid itself
+            let Some(first_id) = items[0].get(id).filter(|first_id| id::is_valid(first_id)) else {
+                return error();
+            };
+            let value_id_check = items.par_iter().all(|item| {
+                item.get(id).is_some_and(|item_id| {
+                    id::is_valid(item_id) && id::same_kind(item_id, first_id)
+                })
+            });
+            if !value_id_check {
+                return error();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads a whole database from `path`, such as a snapshot or the seed file, in the format of its extension
+pub async fn read_db_file(
+    path: &str,
+    id: &str,
+) -> Result<Map<String, Value>, (StatusCode, String)> {
+    let content = match tokio::fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err((StatusCode::NOT_FOUND, "not found".to_string()))
+        }
+        Err(e) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("read [{}] error: {}", path, e),
+            ))
+        }
+    };
+    let value = Format::from_path(path).parse(&content).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("parse [{}] error: {}", path, e),
+        )
+    })?;
+    check_db(&value, id).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    match value {
+        Value::Object(value) => Ok(value),
+        _ => unreachable!("checked by check_db"),
+    }
+}
+
+/// Routes `/api/{name}` to the array or value handlers according to what `name` currently holds,
+/// so collections and values created at runtime are served without restarting.
+async fn dispatch<B>(
+    State(app_state): State<AppState>,
+    Path(name): Path<String>,
+    request: Request<B>,
+) -> Response
+where
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    if name == meta::META_KEY {
+        return (StatusCode::NOT_FOUND, "not found").into_response();
+    }
+    let kind = match app_state.store.kind(&name).await {
+        Ok(kind) => kind,
+        Err(e) => return e.into_response(),
+    };
+    if kind.is_none() && app_state.directory && !persist::is_valid_file_name(&name) {
+        return (StatusCode::BAD_REQUEST, "invalid name for a database file").into_response();
+    }
+    match (request.method().clone(), kind) {
+        (Method::GET, Some(Kind::Array)) => array::list.call(request, app_state).await,
+        (Method::GET, Some(Kind::Value)) => value::get_value.call(request, app_state).await,
+        (Method::POST, Some(Kind::Value)) => value::update_value.call(request, app_state).await,
+        // the first POST of an object creates the collection
+        (Method::POST, _) => array::post_item.call(request, app_state).await,
+        // the first PUT of a non-array value creates the value
+        (Method::PUT, None)
+        | (Method::PUT, Some(Kind::Value))
+        | (Method::PATCH, Some(Kind::Value)) => value::update_value.call(request, app_state).await,
+        (_, Some(Kind::Array)) => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+        _ => (StatusCode::NOT_FOUND, "not found").into_response(),
+    }
 }
@@ -0,0 +1,51 @@
+use axum::http::{header::CONTENT_TYPE, HeaderMap, StatusCode};
+use json_patch::PatchErrorKind;
+use serde_json::Value;
+
+const JSON_PATCH_CONTENT_TYPE: &str = "application/json-patch+json";
+
+/// Applies a JSON Merge Patch (RFC 7396) to `target`
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    match patch {
+        Value::Object(patch) => {
+            if !target.is_object() {
+                *target = Value::Object(serde_json::Map::new());
+            }
+            let target = target.as_object_mut().unwrap();
+            for (key, value) in patch.iter() {
+                if value.is_null() {
+                    target.remove(key);
+                } else {
+                    merge_patch(target.entry(key.as_str()).or_insert(Value::Null), value);
+                }
+            }
+        }
+        _ => *target = patch.clone(),
+    }
+}
+
+pub fn is_json_patch(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().starts_with(JSON_PATCH_CONTENT_TYPE))
+        .unwrap_or(false)
+}
+
+/// Applies a JSON Patch (RFC 6902) to a copy of `target`, the target itself is never touched
+pub fn json_patch(target: &Value, patch: Value) -> Result<Value, (StatusCode, String)> {
+    let patch = serde_json::from_value::<json_patch::Patch>(patch).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("invalid json patch: {}", e),
+        )
+    })?;
+    let mut patched = target.clone();
+    match json_patch::patch(&mut patched, &patch) {
+        Ok(_) => Ok(patched),
+        Err(e) => match e.kind {
+            PatchErrorKind::TestFailed => Err((StatusCode::CONFLICT, e.to_string())),
+            _ => Err((StatusCode::BAD_REQUEST, e.to_string())),
+        },
+    }
+}
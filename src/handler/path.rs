@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+
+use serde_json::{Map, Value};
+
+/// Value at dot-separated `path` in `value`, such as `author.name` or `tags.0`,
+/// where segments name the fields of objects and the indices of arrays
+pub fn get<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(value, |value, segment| match value {
+            Value::Object(object) => object.get(segment),
+            Value::Array(items) => items.get(segment.parse::<usize>().ok()?),
+            _ => None,
+        })
+}
+
+/// Copy of `item` holding only the values at `paths`, nested as they are in `item`.
+/// Elements picked from an array keep their order, the others are left out.
+pub fn project(item: &Value, paths: &[String]) -> Value {
+    let mut projected = Node::Object(BTreeMap::new());
+    for path in paths {
+        if get(item, path).is_some() {
+            projected.insert(item, &path.split('.').collect::<Vec<&str>>());
+        }
+    }
+    projected.into_value()
+}
+
+/// A value being projected, arrays hold the picked elements by index
+enum Node {
+    Value(Value),
+    Object(BTreeMap<String, Node>),
+    Array(BTreeMap<usize, Node>),
+}
+
+impl Node {
+    /// Empty node for the picked parts of `value`
+    fn new(value: &Value) -> Node {
+        match value {
+            Value::Array(_) => Node::Array(BTreeMap::new()),
+            _ => Node::Object(BTreeMap::new()),
+        }
+    }
+
+    /// Picks the value at `segments` of `source`, which exists, into the node of `source`
+    fn insert(&mut self, source: &Value, segments: &[&str]) {
+        let Some((segment, rest)) = segments.split_first() else {
+            *self = Node::Value(source.clone());
+            return;
+        };
+        match (self, source) {
+            (Node::Object(nodes), Value::Object(object)) => {
+                let child = &object[*segment];
+                nodes
+                    .entry(segment.to_string())
+                    .or_insert_with(|| Node::new(child))
+                    .insert(child, rest);
+            }
+            (Node::Array(nodes), Value::Array(items)) => {
+                let index = segment.parse::<usize>().expect("path exists");
+                let child = &items[index];
+                nodes
+                    .entry(index)
+                    .or_insert_with(|| Node::new(child))
+                    .insert(child, rest);
+            }
+            // the whole value is picked already
+            _ => {}
+        }
+    }
+
+    fn into_value(self) -> Value {
+        match self {
+            Node::Value(value) => value,
+            Node::Object(nodes) => Value::Object(
+                nodes
+                    .into_iter()
+                    .map(|(key, node)| (key, node.into_value()))
+                    .collect::<Map<String, Value>>(),
+            ),
+            Node::Array(nodes) => Value::Array(nodes.into_values().map(Node::into_value).collect()),
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde_json::{Map, Value};
+
+use super::{meta, read_db_file, AppState};
+
+async fn read_seed(app_state: &AppState) -> Result<Map<String, Value>, (StatusCode, String)> {
+    match &app_state.seed {
+        Some(seed) => read_db_file(seed, &app_state.id).await,
+        None => Err((StatusCode::NOT_FOUND, "no seed file".to_string())),
+    }
+}
+
+/// Replaces the whole database with the seed file, unsaved changes are discarded
+pub async fn reset(State(app_state): State<AppState>) -> Result<Json<Value>, (StatusCode, String)> {
+    let seed = read_seed(&app_state).await?;
+    app_state.store.import(seed.clone()).await?;
+    log::info!("Database reset to seed");
+    Ok(Value::Object(seed).into())
+}
+
+/// Replaces `name` with its value in the seed file, it is removed if the seed file has none
+pub async fn reset_collection(
+    Path(name): Path<String>,
+    State(app_state): State<AppState>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    if name == meta::META_KEY {
+        return Err((StatusCode::NOT_FOUND, "not found".to_string()));
+    }
+    let mut seed = read_seed(&app_state).await?;
+    match seed.remove(&name) {
+        Some(value) => {
+            app_state.store.put(&name, value.clone()).await?;
+            log::info!("[{}] reset to seed", name);
+            Ok(value.into())
+        }
+        None if app_state.store.remove(&name).await? => {
+            log::info!("[{}] removed, not in seed", name);
+            Ok(Value::Null.into())
+        }
+        None => Err((StatusCode::NOT_FOUND, "not found".to_string())),
+    }
+}
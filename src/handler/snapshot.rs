@@ -0,0 +1,127 @@
+use std::time::SystemTime;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use super::{read_db_file, AppState};
+use crate::persist;
+
+#[derive(Deserialize)]
+pub struct NewSnapshot {
+    pub name: String,
+}
+
+#[derive(Serialize)]
+pub struct SnapshotInfo {
+    pub name: String,
+    pub size: u64,
+    pub created: DateTime<Local>,
+}
+
+/// Named snapshots are kept in `<db_path>.snapshots`
+fn snapshot_dir(app_state: &AppState) -> String {
+    format!("{}.snapshots", app_state.db_path)
+}
+
+fn snapshot_path(app_state: &AppState, name: &str) -> Result<String, (StatusCode, String)> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    if !valid {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "snapshot name may only contain letters, digits, '-', '_' and '.'".to_string(),
+        ));
+    }
+    Ok(format!("{}/{}.json", snapshot_dir(app_state), name))
+}
+
+async fn snapshot_info(name: String, path: &str) -> std::io::Result<SnapshotInfo> {
+    let metadata = tokio::fs::metadata(path).await?;
+    Ok(SnapshotInfo {
+        name,
+        size: metadata.len(),
+        created: metadata.modified().unwrap_or(SystemTime::now()).into(),
+    })
+}
+
+pub async fn list(
+    State(app_state): State<AppState>,
+) -> Result<Json<Vec<SnapshotInfo>>, (StatusCode, String)> {
+    let mut result = Vec::new();
+    let mut entries = match tokio::fs::read_dir(snapshot_dir(&app_state)).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(result.into()),
+        Err(e) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("read snapshots error: {}", e),
+            ))
+        }
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        if let Some(name) = file_name.strip_suffix(".json") {
+            if let Ok(info) = snapshot_info(name.to_string(), &entry.path().to_string_lossy()).await
+            {
+                result.push(info);
+            }
+        }
+    }
+    result.sort_by_key(|info| info.created);
+    Ok(result.into())
+}
+
+pub async fn create(
+    State(app_state): State<AppState>,
+    Json(snapshot): Json<NewSnapshot>,
+) -> Result<Json<SnapshotInfo>, (StatusCode, String)> {
+    let path = snapshot_path(&app_state, &snapshot.name)?;
+    if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+        return Err((StatusCode::CONFLICT, "snapshot exists".to_string()));
+    }
+    let db_value = app_state.store.export().await?;
+    let db_content = serde_json::to_string(&db_value).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("serialize database error: {}", e),
+        )
+    })?;
+    if let Err(e) = tokio::fs::create_dir_all(snapshot_dir(&app_state)).await {
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("create snapshot directory error: {}", e),
+        ));
+    }
+    persist::write_file(&path, &db_content)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    log::info!("Snapshot [{}] created", snapshot.name);
+    snapshot_info(snapshot.name, &path)
+        .await
+        .map(|info| info.into())
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Replaces the whole database with a snapshot
+pub async fn restore(
+    Path(name): Path<String>,
+    State(app_state): State<AppState>,
+) -> Result<Json<SnapshotInfo>, (StatusCode, String)> {
+    let path = snapshot_path(&app_state, &name)?;
+    let value = read_db_file(&path, &app_state.id).await?;
+
+    app_state.store.import(value).await?;
+    log::info!("Snapshot [{}] restored", name);
+    snapshot_info(name, &path)
+        .await
+        .map(|info| info.into())
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
@@ -32,7 +32,7 @@ pub async fn upload(
                     .file_name()
                     .unwrap_or(uuid.clone().as_str())
                     .to_string();
-                let ext_name = name.split('.').last().unwrap_or("").to_string();
+                let ext_name = name.split('.').next_back().unwrap_or("").to_string();
                 log::debug!("found file [{}.{}] = [{}]", uuid, ext_name, name);
 
                 if let Ok(mut file) =
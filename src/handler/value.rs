@@ -1,56 +1,68 @@
 use axum::{
-    extract::{Json, State},
-    http::{StatusCode, Uri},
+    extract::{Json, Path, State},
+    http::{HeaderMap, StatusCode},
 };
 use serde_json::Value;
 
-use crate::handler::get_name;
-
-use super::AppState;
+use super::{
+    patch::{is_json_patch, json_patch},
+    AppState,
+};
 
-pub async fn get_value(uri: Uri, State(app_state): State<AppState>) -> Json<Value> {
-    let name = get_name(uri);
-    app_state
-        .db_value
-        .read()
-        .await
-        .get(&name)
-        .unwrap()
-        .clone()
-        .into()
+pub async fn get_value(
+    Path(name): Path<String>,
+    State(app_state): State<AppState>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    match app_state.store.get_value(&name).await? {
+        Some(value) => Ok(value.into()),
+        None => Err((StatusCode::NOT_FOUND, "not found".to_string())),
+    }
 }
 
 pub async fn update_value(
-    uri: Uri,
+    Path(name): Path<String>,
     State(app_state): State<AppState>,
+    headers: HeaderMap,
     Json(value): Json<Value>,
 ) -> Result<Json<Value>, (StatusCode, String)> {
-    let name = get_name(uri);
-    if value.is_array() || value.is_null() {
+    let is_json_patch = is_json_patch(&headers);
+    if !is_json_patch && (value.is_array() || value.is_null()) {
         return Err((
             StatusCode::BAD_REQUEST,
             "value must be object or plain value, not array nor null".to_string(),
         ));
     }
-    if let Some(db_value) = app_state.db_value.write().await.as_object_mut() {
-        let old_value = db_value.get(&name).unwrap();
-        if (old_value.is_boolean() && !value.is_boolean())
-            || (old_value.is_number() && !value.is_number())
-            || (old_value.is_string() && !value.is_string())
-            || (old_value.is_number() && !value.is_number())
-            || (old_value.is_object() && !value.is_object())
-        {
-            return Err((StatusCode::BAD_REQUEST, "value type mismatch".to_string()));
-        }
-        let mut dirty = app_state.dirty.write().await;
-        db_value.insert(name, value.clone());
-        *dirty = true;
-        drop(dirty);
-        Ok(value.into())
-    } else {
-        Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "unknown error".to_string(),
-        ))
-    }
+    app_state
+        .store
+        .set_value(
+            &name,
+            Box::new(move |old_value| {
+                let value = if is_json_patch {
+                    if old_value.is_null() {
+                        return Err((StatusCode::NOT_FOUND, "not found".to_string()));
+                    }
+                    let patched = json_patch(old_value, value)?;
+                    if patched.is_array() || patched.is_null() {
+                        return Err((
+                            StatusCode::BAD_REQUEST,
+                            "value must be object or plain value, not array nor null".to_string(),
+                        ));
+                    }
+                    patched
+                } else {
+                    value
+                };
+                if (old_value.is_boolean() && !value.is_boolean())
+                    || (old_value.is_number() && !value.is_number())
+                    || (old_value.is_string() && !value.is_string())
+                    || (old_value.is_number() && !value.is_number())
+                    || (old_value.is_object() && !value.is_object())
+                {
+                    return Err((StatusCode::BAD_REQUEST, "value type mismatch".to_string()));
+                }
+                Ok(value)
+            }),
+        )
+        .await
+        .map(Json)
 }
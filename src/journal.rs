@@ -0,0 +1,572 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::ErrorKind;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+use crate::persist;
+
+/// A change of the database, recorded in the journal before it is applied.
+/// Every mutation overwrites what it changes, so replaying a journal twice gives the same result.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Mutation {
+    /// Appends `value` to array `name`, `field` is the id field
+    Insert {
+        name: String,
+        field: String,
+        value: Value,
+    },
+    /// Replaces the item of array `name` having the same id as `value`
+    Update {
+        name: String,
+        field: String,
+        value: Value,
+    },
+    /// Removes the item of array `name` with id `id`
+    Delete {
+        name: String,
+        field: String,
+        id: Value,
+    },
+    /// Sets top-level key `name`
+    SetValue { name: String, value: Value },
+    /// Removes top-level key `name`
+    Remove { name: String },
+    /// Replaces the whole database
+    Replace { value: Map<String, Value> },
+}
+
+impl Mutation {
+    /// Top-level key changed by the mutation, `None` when the whole database is replaced
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Mutation::Insert { name, .. }
+            | Mutation::Update { name, .. }
+            | Mutation::Delete { name, .. }
+            | Mutation::SetValue { name, .. }
+            | Mutation::Remove { name } => Some(name),
+            Mutation::Replace { .. } => None,
+        }
+    }
+}
+
+/// Applies mutations to a database, finding items through the positions of their ids
+/// in the arrays changed so far, so replaying a long journal does not scan them
+pub struct Replay<'a> {
+    db_value: &'a mut Map<String, Value>,
+    arrays: HashMap<String, Positions>,
+}
+
+/// Positions of the items of an array by the json text of their id, with the items removed
+/// so far, which are only taken out once the replay is done
+struct Positions {
+    field: String,
+    /// Position of the first item of each id
+    ids: HashMap<String, usize>,
+    /// Positions of the other items of duplicated ids in stored order
+    duplicates: HashMap<String, VecDeque<usize>>,
+    removed: HashSet<usize>,
+}
+
+impl<'a> Replay<'a> {
+    pub fn new(db_value: &'a mut Map<String, Value>) -> Replay<'a> {
+        Replay {
+            db_value,
+            arrays: HashMap::new(),
+        }
+    }
+
+    pub fn apply(&mut self, mutation: Mutation) {
+        match mutation {
+            Mutation::Insert { name, field, value } | Mutation::Update { name, field, value } => {
+                let Some((items, positions)) = self.array(name, field) else {
+                    return;
+                };
+                match positions.ids.entry(id_key(&value[&positions.field])) {
+                    Entry::Occupied(position) => items[*position.get()] = value,
+                    Entry::Vacant(position) => {
+                        position.insert(items.len());
+                        items.push(value);
+                    }
+                }
+            }
+            Mutation::Delete { name, field, id } => {
+                if !matches!(self.db_value.get(&name), Some(Value::Array(_))) {
+                    return;
+                }
+                let Some((_, positions)) = self.array(name, field) else {
+                    return;
+                };
+                let key = id_key(&id);
+                if let Some(position) = positions.ids.remove(&key) {
+                    positions.removed.insert(position);
+                    // the next item of a duplicated id is found from now on
+                    if let Some(next) = positions
+                        .duplicates
+                        .get_mut(&key)
+                        .and_then(|duplicates| duplicates.pop_front())
+                    {
+                        positions.ids.insert(key, next);
+                    }
+                }
+            }
+            Mutation::SetValue { name, value } => {
+                self.finish_array(&name);
+                self.db_value.insert(name, value);
+            }
+            Mutation::Remove { name } => {
+                self.arrays.remove(&name);
+                self.db_value.remove(&name);
+            }
+            Mutation::Replace { value } => {
+                self.arrays.clear();
+                *self.db_value = value;
+            }
+        }
+    }
+
+    /// Items and positions of array `name` by id `field`, the array is created when missing
+    fn array(&mut self, name: String, field: String) -> Option<(&mut Vec<Value>, &mut Positions)> {
+        if self
+            .arrays
+            .get(&name)
+            .is_some_and(|positions| positions.field != field)
+        {
+            self.finish_array(&name);
+        }
+        let items = self
+            .db_value
+            .entry(name.clone())
+            .or_insert_with(|| Value::Array(Vec::new()))
+            .as_array_mut()?;
+        let positions = self.arrays.entry(name).or_insert_with(|| {
+            let mut ids = HashMap::new();
+            let mut duplicates = HashMap::<String, VecDeque<usize>>::new();
+            for (position, item) in items.iter().enumerate() {
+                // the first item of an id is the one changed
+                match ids.entry(id_key(&item[&field])) {
+                    Entry::Occupied(first) => duplicates
+                        .entry(first.key().clone())
+                        .or_default()
+                        .push_back(position),
+                    Entry::Vacant(first) => {
+                        first.insert(position);
+                    }
+                }
+            }
+            Positions {
+                field,
+                ids,
+                duplicates,
+                removed: HashSet::new(),
+            }
+        });
+        Some((items, positions))
+    }
+
+    /// Takes the removed items out of array `name` and forgets its positions
+    fn finish_array(&mut self, name: &str) {
+        let Some(positions) = self.arrays.remove(name) else {
+            return;
+        };
+        if positions.removed.is_empty() {
+            return;
+        }
+        if let Some(items) = self.db_value.get_mut(name).and_then(|v| v.as_array_mut()) {
+            let mut position = 0;
+            items.retain(|_| {
+                position += 1;
+                !positions.removed.contains(&(position - 1))
+            });
+        }
+    }
+
+    /// Takes the removed items out of the arrays
+    pub fn finish(mut self) {
+        let names = self.arrays.keys().cloned().collect::<Vec<String>>();
+        for name in names {
+            self.finish_array(&name);
+        }
+    }
+}
+
+/// Ids are equal when their json is
+fn id_key(id: &Value) -> String {
+    id.to_string()
+}
+
+/// When journal writes are flushed to disk
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalSync {
+    /// fsync before every request is answered
+    Always,
+    /// fsync once per second, a crash of the process loses nothing but a power loss may
+    Batch,
+}
+
+/// Append-only log of mutations since the last save of the database file.
+///
+/// Saving rotates the journal to `<db_path>.journal.old` while the database is locked, writes the
+/// database file, then removes the old journal, so both journals are replayed after a crash.
+pub struct Journal {
+    path: String,
+    old_path: String,
+    file: File,
+    size: u64,
+    sync: JournalSync,
+}
+
+impl Journal {
+    pub async fn open(db_path: &str, sync: JournalSync) -> std::io::Result<Journal> {
+        let path = format!("{}.journal", db_path);
+        let old_path = format!("{}.journal.old", db_path);
+        let file = open_append(&path).await?;
+        let size = file.metadata().await?.len();
+        Ok(Journal {
+            path,
+            old_path,
+            file,
+            size,
+            sync,
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub async fn append(&mut self, mutation: &Mutation) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(mutation)?;
+        line.push(b'\n');
+        self.file.write_all(&line).await?;
+        self.file.flush().await?;
+        if self.sync == JournalSync::Always {
+            self.file.sync_data().await?;
+        }
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    pub async fn sync(&mut self) -> std::io::Result<()> {
+        self.file.sync_data().await
+    }
+
+    /// Moves the current entries to the old journal, they are covered by the database file being saved
+    pub async fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.sync_all().await?;
+        if tokio::fs::try_exists(&self.old_path).await? {
+            // the previous save did not finish, keep its entries too
+            let content = tokio::fs::read(&self.path).await?;
+            let mut old_file = open_append(&self.old_path).await?;
+            old_file.write_all(&content).await?;
+            old_file.sync_all().await?;
+            self.file.set_len(0).await?;
+        } else {
+            tokio::fs::rename(&self.path, &self.old_path).await?;
+            self.file = open_append(&self.path).await?;
+            persist::sync_dir(&self.path).await?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    /// Removes the old journal once the database file is saved
+    pub async fn remove_old(&mut self) -> std::io::Result<()> {
+        match tokio::fs::remove_file(&self.old_path).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Drops every entry, used when the database is replaced as a whole
+    pub async fn reset(&mut self) -> std::io::Result<()> {
+        self.file.set_len(0).await?;
+        self.file.sync_all().await?;
+        self.size = 0;
+        self.remove_old().await
+    }
+
+    /// Applies the old and the current journal of `db_path` to `db_value`, returns the number of mutations
+    pub async fn replay(db_path: &str, db_value: &mut Map<String, Value>) -> Result<usize, String> {
+        let mut count = 0;
+        let mut replay = Replay::new(db_value);
+        for path in [
+            format!("{}.journal.old", db_path),
+            format!("{}.journal", db_path),
+        ] {
+            let content = match tokio::fs::read_to_string(&path).await {
+                Ok(content) => content,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(format!("Error reading journal [{}]: {}", path, e)),
+            };
+            let complete = content.ends_with('\n');
+            let lines = content.lines().collect::<Vec<&str>>();
+            for (index, line) in lines.iter().enumerate() {
+                match serde_json::from_str::<Mutation>(line) {
+                    Ok(mutation) => replay.apply(mutation),
+                    // the process stopped while writing the last entry, it was never acknowledged
+                    Err(_) if !complete && index + 1 == lines.len() => {
+                        log::warn!("Incomplete last entry of journal [{}] skipped", path);
+                        continue;
+                    }
+                    Err(e) => {
+                        return Err(format!(
+                            "Error parsing journal [{}] line {}: {}",
+                            path,
+                            index + 1,
+                            e
+                        ))
+                    }
+                }
+                count += 1;
+            }
+        }
+        replay.finish();
+        Ok(count)
+    }
+}
+
+async fn open_append(path: &str) -> std::io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn db(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    fn insert(id: u64, title: &str) -> Mutation {
+        Mutation::Insert {
+            name: "posts".to_string(),
+            field: "id".to_string(),
+            value: json!({"id": id, "title": title}),
+        }
+    }
+
+    fn update(id: u64, title: &str) -> Mutation {
+        Mutation::Update {
+            name: "posts".to_string(),
+            field: "id".to_string(),
+            value: json!({"id": id, "title": title}),
+        }
+    }
+
+    fn delete(id: u64) -> Mutation {
+        Mutation::Delete {
+            name: "posts".to_string(),
+            field: "id".to_string(),
+            id: json!(id),
+        }
+    }
+
+    fn replay(db_value: Value, mutations: Vec<Mutation>) -> Value {
+        let mut db_value = db(db_value);
+        let mut replay = Replay::new(&mut db_value);
+        for mutation in mutations {
+            replay.apply(mutation);
+        }
+        replay.finish();
+        Value::Object(db_value)
+    }
+
+    fn journal(mutations: &[Mutation]) -> String {
+        mutations
+            .iter()
+            .map(|mutation| serde_json::to_string(mutation).unwrap() + "\n")
+            .collect()
+    }
+
+    /// Path of a database file in a directory of its own
+    fn db_path(name: &str) -> String {
+        let dir =
+            std::env::temp_dir().join(format!("jserver-journal-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("db.json").to_string_lossy().to_string()
+    }
+
+    fn remove_db_dir(db_path: &str) {
+        std::fs::remove_dir_all(std::path::Path::new(db_path).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn reinsert() {
+        let posts = json!({"posts": [{"id": 1, "title": "a"}, {"id": 2, "title": "b"}]});
+        assert_eq!(
+            replay(posts.clone(), vec![delete(1), insert(1, "c")]),
+            json!({"posts": [{"id": 2, "title": "b"}, {"id": 1, "title": "c"}]})
+        );
+        assert_eq!(
+            replay(
+                posts,
+                vec![insert(3, "c"), delete(3), insert(3, "d"), delete(1)]
+            ),
+            json!({"posts": [{"id": 2, "title": "b"}, {"id": 3, "title": "d"}]})
+        );
+        assert_eq!(
+            replay(json!({}), vec![insert(1, "a"), delete(1), insert(1, "b")]),
+            json!({"posts": [{"id": 1, "title": "b"}]})
+        );
+    }
+
+    #[test]
+    fn update_after_delete() {
+        let posts = json!({"posts": [{"id": 1, "title": "a"}, {"id": 2, "title": "b"}]});
+        assert_eq!(
+            replay(
+                posts.clone(),
+                vec![delete(1), insert(1, "c"), update(1, "d")]
+            ),
+            json!({"posts": [{"id": 2, "title": "b"}, {"id": 1, "title": "d"}]})
+        );
+        // an update finding no item adds it, as its insert was replayed before
+        assert_eq!(
+            replay(posts.clone(), vec![delete(1), update(1, "d")]),
+            json!({"posts": [{"id": 2, "title": "b"}, {"id": 1, "title": "d"}]})
+        );
+        assert_eq!(
+            replay(posts, vec![delete(1), delete(1), update(2, "e"), delete(3)]),
+            json!({"posts": [{"id": 2, "title": "e"}]})
+        );
+    }
+
+    #[test]
+    fn pending_removals() {
+        let posts = json!({"posts": [{"id": 1}, {"id": 2}, {"id": 3}]});
+        let set = |value: Value| Mutation::SetValue {
+            name: "posts".to_string(),
+            value,
+        };
+        assert_eq!(
+            replay(
+                posts.clone(),
+                vec![delete(2), set(json!([{"id": 4}, {"id": 5}]))]
+            ),
+            json!({"posts": [{"id": 4}, {"id": 5}]})
+        );
+        assert_eq!(
+            replay(
+                posts.clone(),
+                vec![
+                    delete(2),
+                    set(json!([{"id": 4}])),
+                    delete(4),
+                    insert(5, "e")
+                ]
+            ),
+            json!({"posts": [{"id": 5, "title": "e"}]})
+        );
+        assert_eq!(
+            replay(
+                posts.clone(),
+                vec![delete(1), set(json!({"id": 1})), delete(1)]
+            ),
+            json!({"posts": {"id": 1}})
+        );
+        let remove = Mutation::Remove {
+            name: "posts".to_string(),
+        };
+        assert_eq!(
+            replay(posts.clone(), vec![delete(1), remove, insert(6, "f")]),
+            json!({"posts": [{"id": 6, "title": "f"}]})
+        );
+        let replace = Mutation::Replace {
+            value: db(json!({"posts": [{"id": 1}, {"id": 2}], "tags": []})),
+        };
+        assert_eq!(
+            replay(posts.clone(), vec![delete(3), replace, delete(2)]),
+            json!({"posts": [{"id": 1}], "tags": []})
+        );
+        // removals by one id field are done before the array is changed by another one
+        let by_code = Mutation::Update {
+            name: "posts".to_string(),
+            field: "code".to_string(),
+            value: json!({"id": 9, "code": "x"}),
+        };
+        assert_eq!(
+            replay(posts, vec![delete(1), by_code, delete(3)]),
+            json!({"posts": [{"id": 2}, {"id": 9, "code": "x"}]})
+        );
+    }
+
+    #[tokio::test]
+    async fn replay_twice() {
+        let db_path = db_path("twice");
+        let old = [insert(3, "c"), delete(1), update(2, "e")];
+        let new = [
+            insert(1, "f"),
+            delete(3),
+            Mutation::SetValue {
+                name: "count".to_string(),
+                value: json!(2),
+            },
+        ];
+        std::fs::write(format!("{}.journal.old", db_path), journal(&old)).unwrap();
+        std::fs::write(format!("{}.journal", db_path), journal(&new)).unwrap();
+        let expected = json!({
+            "count": 2,
+            "posts": [{"id": 2, "title": "e"}, {"id": 1, "title": "f"}],
+        });
+
+        let mut db_value = db(json!({"posts": [{"id": 1, "title": "a"}, {"id": 2, "title": "b"}]}));
+        assert_eq!(Journal::replay(&db_path, &mut db_value).await, Ok(6));
+        assert_eq!(Value::Object(db_value.clone()), expected);
+        // the database file was saved before the journals were removed
+        assert_eq!(Journal::replay(&db_path, &mut db_value).await, Ok(6));
+        assert_eq!(Value::Object(db_value), expected);
+        remove_db_dir(&db_path);
+    }
+
+    #[tokio::test]
+    async fn truncated_last_line() {
+        let db_path = db_path("truncated");
+        let complete = journal(&[insert(1, "a"), update(1, "b")]);
+        let partial = journal(&[update(1, "c")]);
+        let partial = partial.trim_end();
+        std::fs::write(
+            format!("{}.journal", db_path),
+            format!("{}{}", complete, &partial[..partial.len() / 2]),
+        )
+        .unwrap();
+        let mut db_value = Map::new();
+        assert_eq!(Journal::replay(&db_path, &mut db_value).await, Ok(2));
+        assert_eq!(
+            Value::Object(db_value),
+            json!({"posts": [{"id": 1, "title": "b"}]})
+        );
+
+        // only the last line may be cut, by a crash while it was written
+        std::fs::write(
+            format!("{}.journal", db_path),
+            format!("{}\n{}", &partial[..partial.len() / 2], complete),
+        )
+        .unwrap();
+        let error = Journal::replay(&db_path, &mut Map::new())
+            .await
+            .unwrap_err();
+        assert!(error.contains("line 1"), "{}", error);
+        // a cut line that is not the last one was acknowledged
+        std::fs::write(
+            format!("{}.journal", db_path),
+            format!("{}{}\n", complete, &partial[..partial.len() / 2]),
+        )
+        .unwrap();
+        let error = Journal::replay(&db_path, &mut Map::new())
+            .await
+            .unwrap_err();
+        assert!(error.contains("line 3"), "{}", error);
+        remove_db_dir(&db_path);
+    }
+}
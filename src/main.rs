@@ -1,17 +1,31 @@
 use std::io::Write;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use chrono::Local;
 use clap::Parser;
 use serde_json::Value;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
 
+use format::Format;
+use handler::id::IdStrategy;
+use journal::{Journal, JournalSync};
+use persist::{Dirty, Durability, SaveStatus};
+use pool::QueryPool;
+use store::{Database, JsonStore, SqliteStore, Storage, StorageKind};
+
+mod format;
 mod handler;
+mod journal;
+mod persist;
+mod pool;
+mod store;
+mod watcher;
 
 #[tokio::main]
 async fn main() {
-    let args = Args::parse();
+    let mut args = Args::parse();
 
     env_logger::Builder::default()
         .filter_level(if args.debug {
@@ -38,66 +52,147 @@ async fn main() {
         args.public_path
     );
 
-    let mut db_file = match tokio::fs::File::open(&args.db_path).await {
-        Ok(file) => file,
-        Err(e) => match e.kind() {
-            std::io::ErrorKind::NotFound => {
-                log::warn!("Database file not found, creating...");
-                let mut file = tokio::fs::File::create(&args.db_path)
-                    .await
-                    .expect("Error creating database file");
-                file.write_all(b"{}")
-                    .await
-                    .expect("Error writing database file");
-                match tokio::fs::File::open(&args.db_path).await {
-                    Ok(file) => file,
-                    Err(e) => {
-                        log::error!("Error opening database file after create: {}", e);
-                        panic!()
-                    }
-                }
+    // a database directory is given with or without a trailing slash
+    if args.db_path.len() > 1 {
+        args.db_path = args.db_path.trim_end_matches('/').to_string();
+    }
+    let format = args
+        .format
+        .unwrap_or_else(|| Format::from_path(&args.db_path));
+
+    let storage = args
+        .storage
+        .unwrap_or_else(|| StorageKind::from_path(&args.db_path));
+    if storage == StorageKind::Sqlite
+        && (args.journal || args.backups > 0 || args.in_memory || args.format.is_some())
+    {
+        log::error!("--journal, --backups, --in-memory and --format are not supported with sqlite");
+        panic!()
+    }
+
+    let directory = tokio::fs::metadata(&args.db_path)
+        .await
+        .is_ok_and(|metadata| metadata.is_dir());
+    let (mut db_value, db_hash) = if storage == StorageKind::Sqlite {
+        // sqlite keeps the data, the in-memory database stays empty
+        (Value::Object(Default::default()), 0)
+    } else if directory {
+        if args.backups > 0 {
+            log::error!("Backups are not supported for a database directory");
+            panic!()
+        }
+        match persist::load_dir(&args.db_path, format).await {
+            Ok(db_value) => {
+                log::info!("Database directory loaded");
+                (db_value, 0)
             }
-            _ => {
-                log::error!("Error opening database file: {}", e);
+            Err(e) => {
+                log::error!("{}", e);
                 panic!()
             }
-        },
+        }
+    } else {
+        load_db_file(&args, format).await
     };
 
-    let mut db_content = String::new();
-    match db_file.read_to_string(&mut db_content).await {
-        Ok(_) => log::info!("Database file loaded"),
-        Err(e) => {
-            log::error!("Error reading database file: {}", e);
-            panic!()
+    let mut replayed = 0;
+    let journal = if args.journal {
+        if let Some(db_object) = db_value.as_object_mut() {
+            replayed = match Journal::replay(&args.db_path, db_object).await {
+                Ok(count) => count,
+                Err(e) => {
+                    log::error!("{}", e);
+                    panic!()
+                }
+            };
+            if replayed > 0 {
+                log::info!("Journal replayed, {} changes", replayed);
+            }
         }
+        match Journal::open(&args.db_path, args.journal_sync).await {
+            Ok(journal) => Some(Arc::new(Mutex::new(journal))),
+            Err(e) => {
+                log::error!("Error opening journal: {}", e);
+                panic!()
+            }
+        }
+    } else {
+        None
     };
-    drop(db_file);
 
-    let db_value = match serde_json::from_str::<serde_json::Value>(&db_content) {
-        Ok(v) => {
-            log::info!("Database file parsed");
-            v
-        }
-        Err(e) => {
-            log::error!("Error parsing database file: {}", e);
+    if let Some(seed) = &args.seed {
+        if let Err((_, e)) = handler::read_db_file(seed, &args.id).await {
+            log::error!("Error loading seed file [{}]: {}", seed, e);
             panic!()
         }
-    };
-    drop(db_content);
+    }
 
     if let Err(e) = tokio::fs::create_dir_all(&args.public_path).await {
         log::error!("Error creating public path: {}", e);
         panic!()
     }
 
+    if let Err(e) = handler::check_db(&db_value, &args.id) {
+        log::error!("{}", e);
+        panic!();
+    }
+    let db = Arc::new(Database::new(match db_value {
+        Value::Object(db_value) => db_value,
+        _ => unreachable!("checked by check_db"),
+    }));
+    let dirty = Arc::new(RwLock::new(if replayed > 0 {
+        Dirty::all()
+    } else {
+        Dirty::default()
+    }));
+    let store: Arc<dyn Storage> = match storage {
+        StorageKind::Json => Arc::new(JsonStore::new(
+            db.clone(),
+            dirty.clone(),
+            journal.clone(),
+            args.id.clone(),
+            args.id_strategy,
+        )),
+        StorageKind::Sqlite => Arc::new(open_sqlite(&args).await),
+    };
+
     let app_state = AppState {
-        db_value: Arc::new(RwLock::new(db_value)),
-        dirty: Arc::new(RwLock::new(false)),
+        db,
+        dirty,
+        journal,
+        store,
+        queries: QueryPool::new(
+            match args.query_concurrency {
+                0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+                n => n,
+            },
+            Duration::from_millis(args.query_timeout_ms),
+        ),
+        // the save task and the exit never write in memory
+        durability: if args.in_memory {
+            Durability::Off
+        } else {
+            args.durability
+        },
+        read_only: args.read_only,
+        directory,
+        format,
+        save_lock: Arc::new(Mutex::new(())),
+        save_status: Arc::new(RwLock::new(SaveStatus::default())),
+        reject_writes_on_save_error: args.reject_writes_on_save_error,
+        backups: args.backups,
+        seed: args.seed.clone(),
+        db_hash: Arc::new(RwLock::new(db_hash)),
         id: args.id.to_string(),
+        db_path: args.db_path.clone(),
         public_path: args.public_path.clone(),
     };
 
+    if replayed > 0 {
+        // start over with an empty journal
+        let _ = persist::save(&app_state).await;
+    }
+
     let (server_tx, server_rx) = std::sync::mpsc::channel::<bool>();
 
     let app_state_for_server = app_state.clone();
@@ -139,18 +234,63 @@ async fn main() {
         return;
     }
 
-    let db_path = args.db_path.clone();
+    let _watcher = if args.no_watch || storage == StorageKind::Sqlite {
+        None
+    } else if directory {
+        log::warn!("Changes of a database directory by others are not reloaded");
+        None
+    } else {
+        match watcher::watch(app_state.clone(), args.db_path.clone()) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                log::error!("Error watching database file: {}", e);
+                None
+            }
+        }
+    };
+
+    let journal_compact_size = args.journal_compact_m * 1024 * 1024;
     let app_state_for_save = app_state.clone();
     let save_task = tokio::spawn(async move {
+        let second = Duration::from_secs(1);
+        let tick = match app_state_for_save.durability {
+            Durability::Interval(interval) => interval.min(second),
+            _ => second,
+        };
+        let mut last_save = Instant::now();
+        let mut retry_at = None::<Instant>;
         loop {
-            tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-            let mut dirty = app_state_for_save.dirty.write().await;
-            if !*dirty {
-                log::trace!("Database file saving... skipped");
+            tokio::time::sleep(tick).await;
+            if let Some(journal) = &app_state_for_save.journal {
+                // changes are safe in the journal, the database file is only saved to compact it
+                let mut journal = journal.lock().await;
+                if journal.size() < journal_compact_size {
+                    if let Err(e) = journal.sync().await {
+                        log::error!("Error syncing journal: {}", e);
+                    }
+                    continue;
+                }
+            } else {
+                match app_state_for_save.durability {
+                    Durability::Interval(interval) if last_save.elapsed() >= interval => {}
+                    _ => continue,
+                }
+            }
+            if retry_at.is_some_and(|retry_at| Instant::now() < retry_at) {
                 continue;
             }
-            save(app_state_for_save.clone(), &db_path).await;
-            *dirty = false;
+            match persist::save(&app_state_for_save).await {
+                Ok(_) => {
+                    retry_at = None;
+                    last_save = Instant::now();
+                }
+                Err(_) => {
+                    let failures = app_state_for_save.save_status.read().await.failures;
+                    let backoff = persist::backoff(failures);
+                    log::warn!("Database file saving retry in {}s", backoff.as_secs());
+                    retry_at = Some(Instant::now() + backoff);
+                }
+            }
         }
     });
 
@@ -166,34 +306,109 @@ async fn main() {
     log::info!("Ctrl-C received");
     server_tack.abort();
     save_task.abort();
-    let dirty = app_state.dirty.read().await;
-    if *dirty {
-        drop(dirty);
-        save(app_state, &args.db_path).await;
+    if !args.in_memory && persist::save(&app_state).await.is_err() {
+        log::error!("Unsaved changes are lost");
     }
     log::info!("Server exited");
 }
 
-async fn save(app_state: AppState, db_path: &str) {
-    log::info!("Database file saving...");
-    let db_value = app_state.db_value.read().await;
-    let db_content = serde_json::to_string(&*db_value).expect("Error serializing database file");
-    drop(db_value);
-    let temp_file = format!("{}.tmp", db_path);
-    let mut db_file = tokio::fs::File::create(&temp_file)
-        .await
-        .expect("Error creating database file");
-    db_file
-        .write_all(db_content.as_bytes())
-        .await
-        .expect("Error writing database file");
-    tokio::fs::remove_file(db_path)
-        .await
-        .expect("Error removing old database file");
-    tokio::fs::rename(&temp_file, db_path)
-        .await
-        .expect("Error renaming database file");
-    log::info!("Database file saved");
+/// Opens the sqlite database, importing the seed file into an empty one
+async fn open_sqlite(args: &Args) -> SqliteStore {
+    let store = match SqliteStore::open(&args.db_path, args.id.clone(), args.id_strategy) {
+        Ok(store) => store,
+        Err(e) => {
+            log::error!("Error opening sqlite database: {}", e);
+            panic!()
+        }
+    };
+    log::info!("Sqlite database opened");
+    if let Some(seed) = &args.seed {
+        if store.is_empty().await.unwrap_or(false) {
+            let imported = match handler::read_db_file(seed, &args.id).await {
+                Ok(seed) => store.import(seed).await,
+                Err(e) => Err(e),
+            };
+            if let Err((_, e)) = imported {
+                log::error!("Error importing seed file: {}", e);
+                panic!()
+            }
+            log::info!("Seed file imported");
+        }
+    }
+    store
+}
+
+/// Reads and parses the database file, creating it from the seed or empty when missing
+async fn load_db_file(args: &Args, format: Format) -> (Value, u64) {
+    let mut db_file = match tokio::fs::File::open(&args.db_path).await {
+        Ok(file) => file,
+        Err(e) => match e.kind() {
+            std::io::ErrorKind::NotFound if args.in_memory || args.read_only => {
+                log::error!("Database file not found");
+                panic!()
+            }
+            std::io::ErrorKind::NotFound => {
+                log::warn!("Database file not found, creating...");
+                let initial_value = match &args.seed {
+                    Some(seed) => Format::from_path(seed)
+                        .parse(
+                            &tokio::fs::read_to_string(seed)
+                                .await
+                                .expect("Error reading seed file"),
+                        )
+                        .expect("Error parsing seed file"),
+                    None => Value::Object(Default::default()),
+                };
+                let initial_content = format
+                    .serialize(&initial_value)
+                    .expect("Error serializing database file");
+                let mut file = tokio::fs::File::create(&args.db_path)
+                    .await
+                    .expect("Error creating database file");
+                file.write_all(initial_content.as_bytes())
+                    .await
+                    .expect("Error writing database file");
+                match tokio::fs::File::open(&args.db_path).await {
+                    Ok(file) => file,
+                    Err(e) => {
+                        log::error!("Error opening database file after create: {}", e);
+                        panic!()
+                    }
+                }
+            }
+            _ => {
+                log::error!("Error opening database file: {}", e);
+                panic!()
+            }
+        },
+    };
+
+    let mut db_content = String::new();
+    match db_file.read_to_string(&mut db_content).await {
+        Ok(_) => log::info!("Database file loaded"),
+        Err(e) => {
+            log::error!("Error reading database file: {}", e);
+            panic!()
+        }
+    };
+    drop(db_file);
+    let db_hash = watcher::content_hash(&db_content);
+
+    if format.has_comments(&db_content) {
+        log::warn!("Comments in the database file are not kept when it is saved");
+    }
+    let db_value = match format.parse(&db_content) {
+        Ok(v) => {
+            log::info!("Database file parsed");
+            v
+        }
+        Err(e) => {
+            log::error!("Error parsing database file: {}", e);
+            panic!()
+        }
+    };
+    drop(db_content);
+    (db_value, db_hash)
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -207,16 +422,77 @@ pub struct Args {
     public_path: String,
     #[arg(short, long, default_value = "id")]
     id: String,
+    /// How to generate the id of an item posted without one
+    #[arg(long, value_enum, default_value_t = IdStrategy::Auto)]
+    id_strategy: IdStrategy,
+    /// Where the database lives, sqlite for a .sqlite, .sqlite3 or .db database path by default
+    #[arg(long, value_enum)]
+    storage: Option<StorageKind>,
+    /// Format of the database file, picked from its extension by default
+    #[arg(long, value_enum)]
+    format: Option<Format>,
     #[arg(short, long, default_value = "100")]
     max_body_limit_m: usize,
     #[arg(long, default_value_t = false)]
     debug: bool,
+    /// Do not reload the database file when it is changed by others
+    #[arg(long, default_value_t = false)]
+    no_watch: bool,
+    /// When the database file is saved after changes: interval=<ms>, immediate or off (only on exit)
+    #[arg(long, default_value = "interval=1000")]
+    durability: Durability,
+    /// Answer changing requests with 503 while the database file can not be saved
+    #[arg(long, default_value_t = false)]
+    reject_writes_on_save_error: bool,
+    /// Database file that `POST /db/reset` restores, it is never written
+    #[arg(long)]
+    seed: Option<String>,
+    /// Number of timestamped copies of the database file kept next to it, one per save
+    #[arg(long, default_value = "0")]
+    backups: usize,
+    /// Keep changes in memory only, the database file is never written
+    #[arg(long, default_value_t = false, conflicts_with_all = ["journal", "backups"])]
+    in_memory: bool,
+    /// Reject every change with 405
+    #[arg(long, default_value_t = false, conflicts_with_all = ["journal", "backups"])]
+    read_only: bool,
+    /// Record every change in a journal instead of saving the database file every second
+    #[arg(long, default_value_t = false)]
+    journal: bool,
+    /// When journal writes are flushed to disk
+    #[arg(long, value_enum, default_value_t = JournalSync::Always)]
+    journal_sync: JournalSync,
+    /// Journal size that triggers saving the database file
+    #[arg(long, default_value = "16")]
+    journal_compact_m: u64,
+    /// Number of list requests filtering and sorting at the same time, the number of cpus when 0
+    #[arg(long, default_value = "0")]
+    query_concurrency: usize,
+    /// Time a list request may take, waiting for others included, before it is answered with 503
+    #[arg(long, default_value = "30000")]
+    query_timeout_ms: u64,
 }
 
 #[derive(Clone)]
 pub struct AppState {
-    db_value: Arc<RwLock<Value>>,
+    /// Shared with the json store, empty with sqlite
+    db: Arc<Database>,
     id: String,
-    dirty: Arc<RwLock<bool>>,
+    dirty: Arc<RwLock<Dirty>>,
+    store: Arc<dyn Storage>,
+    queries: QueryPool,
+    journal: Option<Arc<Mutex<Journal>>>,
+    durability: Durability,
+    read_only: bool,
+    /// The database is a directory with one file per top-level key
+    directory: bool,
+    format: Format,
+    save_lock: Arc<Mutex<()>>,
+    save_status: Arc<RwLock<SaveStatus>>,
+    reject_writes_on_save_error: bool,
+    backups: usize,
+    seed: Option<String>,
+    db_hash: Arc<RwLock<u64>>,
+    db_path: String,
     public_path: String,
 }
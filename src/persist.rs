@@ -0,0 +1,389 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tokio::io::AsyncWriteExt;
+
+use crate::{format::Format, watcher, AppState};
+
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const BACKUP_TIMESTAMP_FORMAT: &str = "%Y%m%d%H%M%S%3f";
+
+/// When the database file is saved after changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// At most once per interval
+    Interval(Duration),
+    /// Before every change is answered
+    Immediate,
+    /// Only on exit
+    Off,
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "immediate" => Ok(Durability::Immediate),
+            "off" => Ok(Durability::Off),
+            _ => match s.strip_prefix("interval=").map(|ms| ms.parse::<u64>()) {
+                Some(Ok(ms)) if ms > 0 => Ok(Durability::Interval(Duration::from_millis(ms))),
+                _ => Err("expected interval=<ms>, immediate or off".to_string()),
+            },
+        }
+    }
+}
+
+/// Directory of `path`, `.` for a bare file name
+pub fn parent_dir(path: &str) -> &Path {
+    match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// Flushes a rename or a new file in `path`'s directory to disk
+pub async fn sync_dir(path: &str) -> std::io::Result<()> {
+    #[cfg(unix)]
+    tokio::fs::File::open(parent_dir(path))
+        .await?
+        .sync_all()
+        .await?;
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Top-level keys changed since the last save
+#[derive(Default, Debug, Clone)]
+pub struct Dirty {
+    /// The whole database was replaced
+    all: bool,
+    names: HashSet<String>,
+}
+
+impl Dirty {
+    pub fn all() -> Dirty {
+        Dirty {
+            all: true,
+            names: HashSet::new(),
+        }
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.all || !self.names.is_empty()
+    }
+
+    /// `None` marks every key
+    pub fn mark(&mut self, name: Option<&str>) {
+        match name {
+            Some(name) => {
+                self.names.insert(name.to_string());
+            }
+            None => self.all = true,
+        }
+    }
+
+    fn merge(&mut self, other: Dirty) {
+        self.all |= other.all;
+        self.names.extend(other.names);
+    }
+}
+
+/// What a save writes
+enum Content {
+    File(String),
+    /// File content of every changed key, `None` for a removed key.
+    /// When the whole database was replaced, files of other keys are removed.
+    Directory {
+        files: Vec<(String, Option<String>)>,
+        prune: bool,
+    },
+}
+
+/// Reads a database directory, every `<name>.<extension>` file is the top-level key `name`
+pub async fn load_dir(db_path: &str, format: Format) -> Result<Value, String> {
+    let mut db_value = Map::new();
+    let mut entries = tokio::fs::read_dir(db_path)
+        .await
+        .map_err(|e| format!("Error reading database directory: {}", e))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(|e| format!("Error reading database directory: {}", e))?
+    {
+        let path = entry.path();
+        let name = match file_name_key(&path, format) {
+            Some(name) => name,
+            None => continue,
+        };
+        let content = tokio::fs::read_to_string(&path)
+            .await
+            .map_err(|e| format!("Error reading database file [{}]: {}", path.display(), e))?;
+        let value = format
+            .parse(&content)
+            .map_err(|e| format!("Error parsing database file [{}]: {}", path.display(), e))?;
+        db_value.insert(name, value);
+    }
+    Ok(Value::Object(db_value))
+}
+
+fn file_name_key(path: &Path, format: Format) -> Option<String> {
+    if path.extension()?.to_str()? != format.extension() {
+        return None;
+    }
+    Some(path.file_stem()?.to_str()?.to_string())
+}
+
+/// Whether `name` can be stored as a file of a database directory
+pub fn is_valid_file_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains(['/', '\\', '\0'])
+}
+
+/// Outcome of the recent saves, served at `/db/status`
+#[derive(Serialize, Default, Debug, Clone)]
+pub struct SaveStatus {
+    pub last_saved: Option<DateTime<Local>>,
+    pub last_error: Option<String>,
+    /// Number of failed saves since the last successful one
+    pub failures: u32,
+}
+
+/// Delay before retrying a failed save, doubled with every failure
+pub fn backoff(failures: u32) -> Duration {
+    Duration::from_secs(2u64.saturating_pow(failures.min(6))).min(MAX_BACKOFF)
+}
+
+/// Writes the database file if it is dirty.
+///
+/// The content is written to a temporary file, flushed to disk and renamed over the database file,
+/// so there is always a complete database file on disk. When saving fails, the database stays dirty.
+pub async fn save(app_state: &AppState) -> Result<(), String> {
+    let _save_lock = app_state.save_lock.lock().await;
+    let result = try_save(app_state).await;
+    let mut save_status = app_state.save_status.write().await;
+    match &result {
+        Ok(true) => {
+            save_status.last_saved = Some(Local::now());
+            save_status.last_error = None;
+            save_status.failures = 0;
+            log::info!("Database file saved");
+        }
+        Ok(false) => log::trace!("Database file saving... skipped"),
+        Err(e) => {
+            save_status.last_error = Some(e.clone());
+            save_status.failures += 1;
+            log::error!("Error saving database file: {}", e);
+        }
+    }
+    result.map(|_| ())
+}
+
+async fn try_save(app_state: &AppState) -> Result<bool, String> {
+    if !app_state.dirty.read().await.is_dirty() {
+        return Ok(false);
+    }
+    // lock order is keys, key, dirty, journal, db_hash everywhere
+    let frozen = app_state.db.freeze().await;
+    let mut dirty = app_state.dirty.write().await;
+    if !dirty.is_dirty() {
+        return Ok(false);
+    }
+    log::info!("Database file saving...");
+    if let Some(journal) = &app_state.journal {
+        journal
+            .lock()
+            .await
+            .rotate()
+            .await
+            .map_err(|e| format!("Error rotating journal: {}", e))?;
+    }
+    // changes from now on are saved next time
+    let saving = std::mem::take(&mut *dirty);
+    drop(dirty);
+    // the snapshots are serialized while the database is changed again
+    let values = frozen.into_values();
+
+    let result = match content(app_state, &values, &saving) {
+        Ok(content) => write_content(app_state, &content).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        app_state.dirty.write().await.merge(saving);
+        return Err(e);
+    }
+    if let Some(journal) = &app_state.journal {
+        // replaying it again is harmless
+        if let Err(e) = journal.lock().await.remove_old().await {
+            log::warn!("Error removing old journal: {}", e);
+        }
+    }
+    Ok(true)
+}
+
+/// Serializes the saved keys of the snapshots
+fn content(
+    app_state: &AppState,
+    values: &BTreeMap<String, Arc<Value>>,
+    saving: &Dirty,
+) -> Result<Content, String> {
+    if !app_state.directory {
+        let db_value = values
+            .iter()
+            .map(|(name, value)| (name, &**value))
+            .collect::<BTreeMap<&String, &Value>>();
+        return Ok(Content::File(serialize(app_state.format, &db_value)?));
+    }
+    let names = if saving.all {
+        values.keys().cloned().collect()
+    } else {
+        saving.names.iter().cloned().collect::<Vec<String>>()
+    };
+    let mut files = Vec::new();
+    for name in names {
+        let file_content = match values.get(&name) {
+            Some(value) => Some(serialize(app_state.format, &**value)?),
+            None => None,
+        };
+        files.push((name, file_content));
+    }
+    Ok(Content::Directory {
+        files,
+        prune: saving.all,
+    })
+}
+
+fn serialize<T: Serialize>(format: Format, value: &T) -> Result<String, String> {
+    format
+        .serialize(value)
+        .map_err(|e| format!("Error serializing database file: {}", e))
+}
+
+async fn write_content(app_state: &AppState, content: &Content) -> Result<(), String> {
+    match content {
+        Content::File(db_content) => {
+            // remember what we write so the watcher does not reload our own save
+            *app_state.db_hash.write().await = watcher::content_hash(db_content);
+            if app_state.backups > 0 {
+                if let Err(e) = backup(&app_state.db_path, app_state.backups).await {
+                    log::warn!("Error backing up database file: {}", e);
+                }
+            }
+            write_file(&app_state.db_path, db_content).await
+        }
+        Content::Directory { files, prune } => {
+            write_dir(&app_state.db_path, app_state.format, files, *prune).await
+        }
+    }
+}
+
+/// Writes the files of the changed keys of a database directory
+async fn write_dir(
+    db_path: &str,
+    format: Format,
+    files: &[(String, Option<String>)],
+    prune: bool,
+) -> Result<(), String> {
+    let file_path = |name: &str| format!("{}/{}.{}", db_path, name, format.extension());
+    for (name, content) in files {
+        match content {
+            Some(content) => write_file(&file_path(name), content).await?,
+            None => remove_file(&file_path(name)).await?,
+        }
+    }
+    if prune {
+        let names = files
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<HashSet<&str>>();
+        let mut entries = tokio::fs::read_dir(db_path)
+            .await
+            .map_err(|e| format!("Error reading database directory: {}", e))?;
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            match file_name_key(&entry.path(), format) {
+                Some(name) if !names.contains(name.as_str()) => {
+                    remove_file(&file_path(&name)).await?
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn remove_file(path: &str) -> Result<(), String> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(format!("Error removing database file [{}]: {}", path, e))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Keeps the current database file as `<db_path>.<timestamp>.bak` and removes the oldest backups beyond `keep`
+async fn backup(db_path: &str, keep: usize) -> std::io::Result<()> {
+    if !tokio::fs::try_exists(db_path).await? {
+        return Ok(());
+    }
+    let backup_path = format!(
+        "{}.{}.bak",
+        db_path,
+        Local::now().format(BACKUP_TIMESTAMP_FORMAT)
+    );
+    // the database file is replaced by a rename, so a hard link keeps the old content without copying it
+    if tokio::fs::hard_link(db_path, &backup_path).await.is_err() {
+        tokio::fs::copy(db_path, &backup_path).await?;
+    }
+
+    let prefix = format!(
+        "{}.",
+        Path::new(db_path)
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+    );
+    let mut backups = Vec::new();
+    let mut entries = tokio::fs::read_dir(parent_dir(db_path)).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with(&prefix) && name.ends_with(".bak") {
+            backups.push(entry.path());
+        }
+    }
+    // timestamps sort by name
+    backups.sort();
+    let remove_count = backups.len().saturating_sub(keep);
+    for old_backup in backups.iter().take(remove_count) {
+        tokio::fs::remove_file(old_backup).await?;
+    }
+    Ok(())
+}
+
+/// Writes `db_content` to a temporary file, flushes it and renames it to `db_path`
+pub async fn write_file(db_path: &str, db_content: &str) -> Result<(), String> {
+    let temp_file = format!("{}.tmp", db_path);
+    let mut db_file = tokio::fs::File::create(&temp_file)
+        .await
+        .map_err(|e| format!("Error creating database file: {}", e))?;
+    db_file
+        .write_all(db_content.as_bytes())
+        .await
+        .map_err(|e| format!("Error writing database file: {}", e))?;
+    db_file
+        .sync_all()
+        .await
+        .map_err(|e| format!("Error syncing database file: {}", e))?;
+    drop(db_file);
+    tokio::fs::rename(&temp_file, db_path)
+        .await
+        .map_err(|e| format!("Error renaming database file: {}", e))?;
+    sync_dir(db_path)
+        .await
+        .map_err(|e| format!("Error syncing database directory: {}", e))
+}
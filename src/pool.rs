@@ -0,0 +1,65 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use tokio::sync::Semaphore;
+
+/// Runs the filtering, sorting and serializing of list requests on the blocking threads,
+/// so large collections never hold up the workers serving other connections
+#[derive(Clone)]
+pub struct QueryPool {
+    permits: Arc<Semaphore>,
+    timeout: Duration,
+}
+
+impl QueryPool {
+    pub fn new(concurrency: usize, timeout: Duration) -> QueryPool {
+        QueryPool {
+            permits: Arc::new(Semaphore::new(concurrency)),
+            timeout,
+        }
+    }
+
+    /// Runs `query` when fewer queries than the concurrency limit are running.
+    /// When it does not finish within the timeout, waiting included, it fails with 503
+    /// and the flag passed to `query` is set, so it can stop early.
+    pub async fn run<T, F>(&self, query: F) -> Result<T, (StatusCode, String)>
+    where
+        F: FnOnce(Arc<AtomicBool>) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let flag = cancelled.clone();
+        let permits = self.permits.clone();
+        let task = async move {
+            let permit = permits
+                .acquire_owned()
+                .await
+                .expect("query permits are never closed");
+            tokio::task::spawn_blocking(move || {
+                // a query that timed out keeps its permit until it stops
+                let _permit = permit;
+                query(flag)
+            })
+            .await
+        };
+        match tokio::time::timeout(self.timeout, task).await {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(e)) => {
+                log::error!("Query failed: {}", e);
+                Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "query failed".to_string(),
+                ))
+            }
+            Err(_) => {
+                cancelled.store(true, Ordering::Relaxed);
+                Err((
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "query timed out".to_string(),
+                ))
+            }
+        }
+    }
+}
@@ -0,0 +1,637 @@
+use std::cmp::Ordering;
+use std::collections::{hash_map, BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::ops::Bound;
+
+use serde_json::Value;
+
+use super::Lookup;
+use crate::handler::{
+    meta::{Collection, IndexKind},
+    path,
+};
+
+/// Key of an item id, ids are unsigned integers or strings
+#[derive(Hash, PartialEq, Eq, Clone)]
+enum IdKey {
+    Number(u64),
+    String(String),
+}
+
+impl IdKey {
+    fn of(id: &Value) -> Option<IdKey> {
+        match id {
+            Value::String(s) => Some(IdKey::String(s.clone())),
+            _ => id.as_u64().map(IdKey::Number),
+        }
+    }
+}
+
+/// Positions of the items of a collection by id and its largest integer id
+pub struct IdIndex {
+    /// Id field the index was built for, changing the `_meta` section may change it
+    pub field: String,
+    /// Position of the first item of each id
+    positions: HashMap<IdKey, usize>,
+    /// Positions of the other items of duplicated ids in stored order
+    duplicates: HashMap<IdKey, VecDeque<usize>>,
+    /// Integer ids, the last one is the largest
+    numbers: BTreeSet<u64>,
+}
+
+impl IdIndex {
+    pub fn build(items: &[Value], field: &str) -> IdIndex {
+        let mut index = IdIndex {
+            field: field.to_string(),
+            positions: HashMap::with_capacity(items.len()),
+            duplicates: HashMap::new(),
+            numbers: BTreeSet::new(),
+        };
+        for (position, item) in items.iter().enumerate() {
+            index.insert(item, position);
+        }
+        index
+    }
+
+    /// Position of the item with an id taken from the request path,
+    /// which matches a string id or, when it is a number, an integer id
+    pub fn position(&self, id: &str) -> Option<usize> {
+        self.positions
+            .get(&IdKey::String(id.to_string()))
+            .or_else(|| self.positions.get(&IdKey::Number(id.parse().ok()?)))
+            .copied()
+    }
+
+    pub fn contains(&self, id: &Value) -> bool {
+        IdKey::of(id).is_some_and(|key| self.positions.contains_key(&key))
+    }
+
+    pub fn max_id(&self) -> u64 {
+        self.numbers.last().copied().unwrap_or(0)
+    }
+
+    /// Records `item` stored at `position`, behind the items stored so far
+    fn insert(&mut self, item: &Value, position: usize) {
+        let Some(key) = IdKey::of(&item[&self.field]) else {
+            return;
+        };
+        if let IdKey::Number(number) = key {
+            self.numbers.insert(number);
+        }
+        // the first item of a duplicated id is the one found, as replaying a journal does
+        match self.positions.entry(key) {
+            hash_map::Entry::Occupied(first) => self
+                .duplicates
+                .entry(first.key().clone())
+                .or_default()
+                .push_back(position),
+            hash_map::Entry::Vacant(first) => {
+                first.insert(position);
+            }
+        }
+    }
+
+    /// Forgets `removed`, which was found by its id at `position`,
+    /// the items behind it moved down by one
+    fn remove(&mut self, removed: &Value, position: usize) {
+        let Some(key) = IdKey::of(&removed[&self.field]) else {
+            return;
+        };
+        let moved = self
+            .positions
+            .values_mut()
+            .chain(self.duplicates.values_mut().flatten());
+        for moved in moved.filter(|moved| **moved > position) {
+            *moved -= 1;
+        }
+        // the next item of a duplicated id takes the place of the removed one
+        match self.duplicates.get_mut(&key).and_then(VecDeque::pop_front) {
+            Some(next) => {
+                if self.duplicates[&key].is_empty() {
+                    self.duplicates.remove(&key);
+                }
+                self.positions.insert(key, next);
+            }
+            None => {
+                self.positions.remove(&key);
+                if let IdKey::Number(number) = key {
+                    self.numbers.remove(&number);
+                }
+            }
+        }
+    }
+}
+
+/// A number of a field value, json numbers are never NaN
+#[derive(Clone, Copy)]
+struct Number(f64);
+
+impl Number {
+    fn new(number: f64) -> Number {
+        // -0 equals 0
+        Number(if number == 0.0 { 0.0 } else { number })
+    }
+}
+
+impl PartialEq for Number {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Number {}
+
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Number {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl Hash for Number {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.to_bits().hash(state);
+    }
+}
+
+/// Key of a field value, values of the same kind are ordered the way list requests sort them
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum ValueKey {
+    /// Null, arrays and objects
+    Other,
+    Bool(bool),
+    Number(Number),
+    String(String),
+}
+
+impl ValueKey {
+    fn of(value: &Value) -> ValueKey {
+        match value {
+            Value::Bool(b) => ValueKey::Bool(*b),
+            Value::Number(n) => ValueKey::Number(Number::new(n.as_f64().unwrap_or_default())),
+            Value::String(s) => ValueKey::String(s.clone()),
+            _ => ValueKey::Other,
+        }
+    }
+
+    fn same_kind(&self, other: &ValueKey) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
+enum Buckets {
+    Hash(HashMap<ValueKey, HashSet<IdKey>>),
+    Ordered(BTreeMap<ValueKey, HashSet<IdKey>>),
+}
+
+/// Ids of the items of a collection by the value at one path, items without it are left out
+struct FieldIndex {
+    field: String,
+    kind: IndexKind,
+    buckets: Buckets,
+}
+
+impl FieldIndex {
+    fn new(field: &str, kind: IndexKind) -> FieldIndex {
+        FieldIndex {
+            field: field.to_string(),
+            kind,
+            buckets: match kind {
+                IndexKind::Hash => Buckets::Hash(HashMap::new()),
+                IndexKind::Ordered => Buckets::Ordered(BTreeMap::new()),
+            },
+        }
+    }
+
+    fn insert(&mut self, item: &Value, id: &Value) {
+        let (Some(value), Some(id)) = (path::get(item, &self.field), IdKey::of(id)) else {
+            return;
+        };
+        let key = ValueKey::of(value);
+        match &mut self.buckets {
+            Buckets::Hash(buckets) => buckets.entry(key).or_default().insert(id),
+            Buckets::Ordered(buckets) => buckets.entry(key).or_default().insert(id),
+        };
+    }
+
+    fn remove(&mut self, item: &Value, id: &Value) {
+        let (Some(value), Some(id)) = (path::get(item, &self.field), IdKey::of(id)) else {
+            return;
+        };
+        let key = ValueKey::of(value);
+        let emptied = match &mut self.buckets {
+            Buckets::Hash(buckets) => buckets
+                .get_mut(&key)
+                .map(|ids| ids.remove(&id) && ids.is_empty()),
+            Buckets::Ordered(buckets) => buckets
+                .get_mut(&key)
+                .map(|ids| ids.remove(&id) && ids.is_empty()),
+        };
+        if emptied == Some(true) {
+            match &mut self.buckets {
+                Buckets::Hash(buckets) => buckets.remove(&key),
+                Buckets::Ordered(buckets) => buckets.remove(&key),
+            };
+        }
+    }
+
+    fn get(&self, key: &ValueKey) -> Option<&HashSet<IdKey>> {
+        match &self.buckets {
+            Buckets::Hash(buckets) => buckets.get(key),
+            Buckets::Ordered(buckets) => buckets.get(key),
+        }
+    }
+
+    /// Ids of the items that pass `lookup`, `None` when this index can not answer it.
+    /// A value of a query equals the same string, and the number or boolean it spells.
+    fn find(&self, lookup: &Lookup) -> Option<Vec<&HashSet<IdKey>>> {
+        match lookup {
+            Lookup::Equal { values, .. } => {
+                let mut found = Vec::new();
+                for value in values {
+                    found.extend(self.get(&ValueKey::String(value.clone())));
+                    if let Ok(number) = value.parse::<f64>() {
+                        if number.is_finite() {
+                            found.extend(self.get(&ValueKey::Number(Number::new(number))));
+                        }
+                    }
+                    if let Ok(b) = value.parse::<bool>() {
+                        found.extend(self.get(&ValueKey::Bool(b)));
+                    }
+                }
+                Some(found)
+            }
+            Lookup::Range { lower, upper, .. } => {
+                let Buckets::Ordered(buckets) = &self.buckets else {
+                    return None;
+                };
+                let bound = |bound: &Bound<f64>, unbounded: f64| match bound {
+                    Bound::Included(n) => Bound::Included(ValueKey::Number(Number::new(*n))),
+                    Bound::Excluded(n) => Bound::Excluded(ValueKey::Number(Number::new(*n))),
+                    Bound::Unbounded => Bound::Included(ValueKey::Number(Number(unbounded))),
+                };
+                Some(
+                    buckets
+                        .range((bound(lower, f64::NEG_INFINITY), bound(upper, f64::INFINITY)))
+                        .map(|(_, ids)| ids)
+                        .collect(),
+                )
+            }
+        }
+    }
+}
+
+/// The id index and the field indexes of a collection
+pub struct Indexes {
+    pub id: IdIndex,
+    fields: Vec<FieldIndex>,
+}
+
+impl Indexes {
+    pub fn build(items: &[Value], collection: &Collection) -> Indexes {
+        let mut indexes = Indexes {
+            id: IdIndex::build(items, &collection.id),
+            fields: collection
+                .indexes
+                .iter()
+                .map(|(field, kind)| FieldIndex::new(field, *kind))
+                .collect(),
+        };
+        for item in items {
+            for index in indexes.fields.iter_mut() {
+                index.insert(item, &item[&collection.id]);
+            }
+        }
+        indexes
+    }
+
+    /// Whether the indexes were built for the configuration of `collection`, which `_meta` may change
+    pub fn is_built_for(&self, collection: &Collection) -> bool {
+        self.id.field == collection.id
+            && self.fields.len() == collection.indexes.len()
+            && self
+                .fields
+                .iter()
+                .zip(collection.indexes.iter())
+                .all(|(index, (field, kind))| &index.field == field && index.kind == *kind)
+    }
+
+    /// Records `item` stored at `position`
+    pub fn insert(&mut self, item: &Value, position: usize) {
+        self.id.insert(item, position);
+        let id = &item[&self.id.field];
+        for index in self.fields.iter_mut() {
+            index.insert(item, id);
+        }
+    }
+
+    /// Records that `old` was replaced by `new`, which has the same id
+    pub fn update(&mut self, old: &Value, new: &Value) {
+        let id = &old[&self.id.field];
+        for index in self.fields.iter_mut() {
+            index.remove(old, id);
+            index.insert(new, id);
+        }
+    }
+
+    /// Forgets `removed`, which was found by its id at `position`, the items behind it moved down by one
+    pub fn remove(&mut self, removed: &Value, position: usize) {
+        self.id.remove(removed, position);
+        let id = &removed[&self.id.field];
+        for index in self.fields.iter_mut() {
+            index.remove(removed, id);
+        }
+    }
+
+    /// Positions in stored order of the items that may pass every lookup,
+    /// found by the index with the fewest of them, `None` when no index answers a lookup
+    pub fn find(&self, lookups: &[Lookup]) -> Option<Vec<usize>> {
+        let found = lookups
+            .iter()
+            .filter_map(|lookup| {
+                let field = match lookup {
+                    Lookup::Equal { field, .. } | Lookup::Range { field, .. } => field,
+                };
+                self.fields
+                    .iter()
+                    .find(|index| &index.field == field)?
+                    .find(lookup)
+            })
+            .min_by_key(|found| found.iter().map(|ids| ids.len()).sum::<usize>())?;
+        let mut positions = found
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.id.positions.get(id).copied())
+            .collect::<Vec<usize>>();
+        // values equal to several values of a lookup are found once per value
+        positions.sort_unstable();
+        positions.dedup();
+        Some(positions)
+    }
+
+    /// Positions of all `len` items sorted by `field` the way list requests sort them,
+    /// `None` when `field` has no ordered index or holds values of several kinds,
+    /// which do not compare
+    pub fn sorted(&self, field: &str, ascending: bool, len: usize) -> Option<Vec<usize>> {
+        let Buckets::Ordered(buckets) = &self
+            .fields
+            .iter()
+            .find(|index| index.field == field && index.kind == IndexKind::Ordered)?
+            .buckets
+        else {
+            return None;
+        };
+        let (first, _) = buckets.first_key_value()?;
+        let (last, _) = buckets.last_key_value()?;
+        if *first == ValueKey::Other || !first.same_kind(last) {
+            return None;
+        }
+
+        let mut indexed = vec![false; len];
+        let mut sorted = Vec::with_capacity(len);
+        for ids in buckets.values() {
+            let mut positions = ids
+                .iter()
+                .filter_map(|id| self.id.positions.get(id).copied())
+                .collect::<Vec<usize>>();
+            // equal items keep their stored order
+            positions.sort_unstable();
+            for &position in positions.iter() {
+                indexed[position] = true;
+            }
+            sorted.push(positions);
+        }
+        if !ascending {
+            sorted.reverse();
+        }
+        // items without the field come first in ascending order and last otherwise
+        let missing = (0..len).filter(|&position| !indexed[position]);
+        let mut order = Vec::with_capacity(len);
+        if ascending {
+            order.extend(missing);
+            order.extend(sorted.into_iter().flatten());
+        } else {
+            order.extend(sorted.into_iter().flatten());
+            order.extend(missing);
+        }
+        Some(order)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::{array::compare_by, filter::Predicate, id::IdStrategy};
+    use crate::journal::{Mutation, Replay};
+    use serde_json::{json, Map};
+
+    fn items() -> Vec<Value> {
+        vec![
+            json!({"id": 1, "v": 5, "n": 3, "s": "b", "b": true, "a": {"x": 1}}),
+            json!({"id": 2, "v": "5", "n": -0.0, "s": "5", "b": false}),
+            json!({"id": 3, "v": 5.0, "n": 0, "s": "a", "b": true, "a": {"x": "1"}}),
+            json!({"id": 4, "v": true, "s": "true", "b": false}),
+            json!({"id": 5, "v": "true", "n": 1.5, "s": "", "a": {"x": true}}),
+            json!({"id": 6, "v": null, "n": -2, "s": "b", "b": true}),
+            json!({"id": 7, "v": [5], "n": 3.0, "b": true, "a": null}),
+            json!({"id": "x", "v": -0.0, "n": 0.0, "s": "-0", "b": false}),
+            json!({"id": 8, "v": 0, "n": 1e20, "s": "0"}),
+        ]
+    }
+
+    fn indexes(items: &[Value]) -> Indexes {
+        let mut collection = crate::handler::meta::resolve(None, "id", IdStrategy::Auto);
+        collection.indexes = [
+            ("v", IndexKind::Ordered),
+            ("n", IndexKind::Ordered),
+            ("s", IndexKind::Ordered),
+            ("b", IndexKind::Ordered),
+            ("a.x", IndexKind::Hash),
+        ]
+        .into_iter()
+        .map(|(field, kind)| (field.to_string(), kind))
+        .collect();
+        Indexes::build(items, &collection)
+    }
+
+    /// Positions `find` answers with after filtering, `None` when no index answers,
+    /// and the positions of a plain filter over all items
+    fn find(params: &[(&str, &str)]) -> (Option<Vec<usize>>, Vec<usize>) {
+        let params = params
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>();
+        let predicate = Predicate::parse(&params).unwrap();
+        let items = items();
+        let matching = |positions: &mut dyn Iterator<Item = usize>| {
+            positions
+                .filter(|&p| predicate.matches(&items[p]))
+                .collect::<Vec<usize>>()
+        };
+        let found = indexes(&items)
+            .find(&predicate.lookups())
+            .map(|positions| matching(&mut positions.into_iter()));
+        (found, matching(&mut (0..items.len())))
+    }
+
+    fn assert_found(params: &[(&str, &str)]) {
+        let (found, plain) = find(params);
+        assert_eq!(found, Some(plain), "{:?}", params);
+    }
+
+    fn sorted(field: &str, order: &str) -> Option<Vec<usize>> {
+        let items = items();
+        let sorted = indexes(&items).sorted(field, order == "asc", items.len())?;
+        let mut plain = (0..items.len()).collect::<Vec<usize>>();
+        plain.sort_by(|a, b| compare_by(&items[*a], &items[*b], field, order));
+        assert_eq!(sorted, plain, "{} {}", field, order);
+        Some(sorted)
+    }
+
+    #[test]
+    fn find_numbers() {
+        assert_found(&[("v", "5")]);
+        assert_found(&[("v", "5.0")]);
+        assert_found(&[("n", "0")]);
+        assert_found(&[("n", "-0")]);
+        assert_found(&[("v", "-0")]);
+        assert_found(&[("n", "1e20")]);
+        assert_found(&[("n_gt", "0")]);
+        assert_found(&[("n_gte", "-0")]);
+        assert_found(&[("n_lt", "3")]);
+        assert_found(&[("n_lte", "3"), ("n_gt", "-2")]);
+        assert_found(&[("v_gte", "0")]);
+        assert_found(&[("s_gt", "0")]);
+        assert_eq!(find(&[("v", "5")]).1, vec![0, 1, 2]);
+        assert_eq!(find(&[("n", "-0")]).1, vec![1, 2, 7]);
+    }
+
+    #[test]
+    fn find_strings_and_bools() {
+        assert_found(&[("s", "b")]);
+        assert_found(&[("s", "5")]);
+        assert_found(&[("s", "")]);
+        assert_found(&[("v", "true")]);
+        assert_found(&[("b", "true")]);
+        assert_found(&[("b", "false")]);
+        assert_found(&[("s", "true")]);
+        assert_found(&[("a.x", "1")]);
+        assert_found(&[("a.x", "true")]);
+        assert_found(&[("s", "missing")]);
+        assert_eq!(find(&[("v", "true")]).1, vec![3, 4]);
+    }
+
+    #[test]
+    fn find_alternatives() {
+        assert_found(&[("v", "5"), ("v", "true")]);
+        assert_found(&[("v_in", "5,0,true")]);
+        assert_found(&[("s_in", "b,5"), ("b", "true")]);
+        assert_found(&[("_where", r#"{"v": 5}"#)]);
+        assert_found(&[("_where", r#"{"v": "5"}"#)]);
+        assert_found(&[("_where", r#"{"v": {"$in": [5, true]}}"#)]);
+        assert_found(&[("_where", r#"{"$or": [{"s": "a"}, {"s": "b"}]}"#)]);
+    }
+
+    #[test]
+    fn find_unanswered() {
+        assert_eq!(find(&[("v_ne", "5")]).0, None);
+        assert_eq!(find(&[("missing", "5")]).0, None);
+        assert_eq!(find(&[("v", "5"), ("s", "b")]).0.map(|p| p.len()), Some(1));
+        // a hash index answers equality only
+        assert_eq!(find(&[("a.x_gt", "0")]).0, None);
+        assert_eq!(
+            find(&[("_where", r#"{"$or": [{"s": "a"}, {"b": true}]}"#)]).0,
+            None
+        );
+    }
+
+    #[test]
+    fn sorted_by_kind() {
+        // numbers with -0 and missing values
+        assert!(sorted("n", "asc").is_some());
+        assert!(sorted("n", "desc").is_some());
+        // strings, numeric ones included, and missing values
+        assert!(sorted("s", "asc").is_some());
+        assert!(sorted("s", "desc").is_some());
+        assert!(sorted("b", "asc").is_some());
+        assert!(sorted("b", "desc").is_some());
+        assert_eq!(sorted("n", "asc").unwrap()[..2], [3, 5]);
+    }
+
+    #[test]
+    fn sorted_unanswered() {
+        // values of several kinds do not compare
+        assert_eq!(sorted("v", "asc"), None);
+        assert_eq!(sorted("a.x", "asc"), None);
+        assert_eq!(sorted("missing", "asc"), None);
+    }
+
+    #[test]
+    fn duplicated_ids_as_replayed() {
+        let stored = vec![
+            json!({"id": 1, "v": "a"}),
+            json!({"id": 2, "v": "a"}),
+            json!({"id": 1, "v": "b"}),
+            json!({"id": 1, "v": "c"}),
+        ];
+        let mut items = stored.clone();
+        let mut index = indexes(&items);
+        let mut db_value = Map::from_iter([("posts".to_string(), Value::Array(stored))]);
+        let mut replay = Replay::new(&mut db_value);
+        let changes = [
+            ("1", Some(json!({"id": 1, "v": "x"}))),
+            ("1", None),
+            ("1", Some(json!({"id": 1, "v": "y"}))),
+            ("2", None),
+            ("1", None),
+            ("1", Some(json!({"id": 1, "v": "z"}))),
+        ];
+        for (id, value) in changes {
+            let position = index.id.position(id).unwrap();
+            let mutation = match value {
+                Some(value) => {
+                    index.update(&items[position], &value);
+                    items[position] = value.clone();
+                    Mutation::Update {
+                        name: "posts".to_string(),
+                        field: "id".to_string(),
+                        value,
+                    }
+                }
+                None => {
+                    let removed = items.remove(position);
+                    index.remove(&removed, position);
+                    Mutation::Delete {
+                        name: "posts".to_string(),
+                        field: "id".to_string(),
+                        id: removed["id"].clone(),
+                    }
+                }
+            };
+            replay.apply(mutation);
+        }
+        replay.finish();
+        assert_eq!(items, vec![json!({"id": 1, "v": "z"})]);
+        assert_eq!(db_value["posts"], Value::Array(items));
+    }
+
+    #[test]
+    fn remove_moves_positions() {
+        let mut items = (1..=5).map(|id| json!({"id": id})).collect::<Vec<Value>>();
+        let mut index = indexes(&items);
+        for id in ["2", "5", "4"] {
+            let position = index.id.position(id).unwrap();
+            let removed = items.remove(position);
+            index.remove(&removed, position);
+        }
+        assert_eq!(index.id.position("1"), Some(0));
+        assert_eq!(index.id.position("3"), Some(1));
+        assert_eq!(index.id.position("2"), None);
+        assert_eq!(index.id.max_id(), 3);
+    }
+}
@@ -0,0 +1,458 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use rayon::prelude::*;
+use serde_json::{Map, Value};
+use tokio::sync::{Mutex, OwnedRwLockReadGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use super::{
+    assign_id, index::Indexes, not_array, not_found, not_object, page, Kind, Page, Query, Storage,
+    StoreResult, Update,
+};
+use crate::{
+    handler::{
+        id::IdStrategy,
+        meta::{self, Collection},
+    },
+    journal::{Journal, Mutation},
+    persist::Dirty,
+};
+
+type Slot = Arc<RwLock<Entry>>;
+
+pub type Keys = BTreeMap<String, Slot>;
+
+/// Value of a top-level key, copied on write only while a snapshot of it is still in use
+pub struct Entry {
+    value: Arc<Value>,
+    /// Built on first use of a collection, dropped when the value is replaced
+    index: Option<Indexes>,
+}
+
+impl Entry {
+    /// The items of a collection with their indexes, which are built when missing
+    /// or built for another configuration
+    fn indexed(&mut self, collection: &Collection) -> StoreResult<(&mut Arc<Value>, &mut Indexes)> {
+        let items = items(&self.value)?;
+        if !matches!(&self.index, Some(index) if index.is_built_for(collection)) {
+            self.index = Some(Indexes::build(items, collection));
+        }
+        Ok((
+            &mut self.value,
+            self.index.as_mut().expect("index is built"),
+        ))
+    }
+}
+
+/// The whole database held in memory with a lock for every top-level key,
+/// so requests on unrelated collections never wait for each other.
+///
+/// Changing a key holds the read lock of `keys` and the write lock of the key,
+/// adding or removing keys holds the write lock of `keys`.
+/// Lock order is keys, key, dirty, journal, db_hash everywhere.
+pub struct Database {
+    keys: RwLock<Keys>,
+}
+
+/// Snapshots of every key, the keys can not change until it is dropped
+pub struct Frozen<'a> {
+    values: BTreeMap<String, Arc<Value>>,
+    _keys: RwLockReadGuard<'a, Keys>,
+    _guards: Vec<OwnedRwLockReadGuard<Entry>>,
+}
+
+impl Frozen<'_> {
+    /// Releases the locks, the snapshots stay as they are
+    pub fn into_values(self) -> BTreeMap<String, Arc<Value>> {
+        self.values
+    }
+}
+
+impl Database {
+    pub fn new(db_value: Map<String, Value>) -> Database {
+        Database {
+            keys: RwLock::new(Database::keys(db_value)),
+        }
+    }
+
+    pub fn keys(db_value: Map<String, Value>) -> Keys {
+        db_value
+            .into_iter()
+            .map(|(name, value)| (name, slot(value)))
+            .collect()
+    }
+
+    async fn slot(&self, name: &str) -> Option<Slot> {
+        self.keys.read().await.get(name).cloned()
+    }
+
+    /// Snapshot of key `name`, the lock is only held to clone it
+    pub async fn get(&self, name: &str) -> Option<Arc<Value>> {
+        let value = self.slot(name).await?.read().await.value.clone();
+        Some(value)
+    }
+
+    /// Waits for the changes in progress and holds off new ones, so the snapshots are consistent
+    /// with the dirty keys and the journal
+    pub async fn freeze(&self) -> Frozen<'_> {
+        let keys = self.keys.read().await;
+        let mut values = BTreeMap::new();
+        let mut guards = Vec::with_capacity(keys.len());
+        for (name, slot) in keys.iter() {
+            let guard = slot.clone().read_owned().await;
+            values.insert(name.clone(), guard.value.clone());
+            guards.push(guard);
+        }
+        Frozen {
+            values,
+            _keys: keys,
+            _guards: guards,
+        }
+    }
+
+    /// Every key locked, for replacing the whole database
+    pub async fn write(&self) -> RwLockWriteGuard<'_, Keys> {
+        self.keys.write().await
+    }
+}
+
+fn slot(value: Value) -> Slot {
+    Arc::new(RwLock::new(Entry {
+        value: Arc::new(value),
+        index: None,
+    }))
+}
+
+fn items(value: &Value) -> StoreResult<&Vec<Value>> {
+    value.as_array().ok_or_else(not_array)
+}
+
+/// Copies the items first when a snapshot of them is still in use
+fn items_mut(value: &mut Arc<Value>) -> &mut Vec<Value> {
+    Arc::make_mut(value)
+        .as_array_mut()
+        .expect("checked to be an array")
+}
+
+/// The database held in memory, saved to the database file or directory by `persist::save`
+pub struct JsonStore {
+    db: Arc<Database>,
+    dirty: Arc<RwLock<Dirty>>,
+    journal: Option<Arc<Mutex<Journal>>>,
+    id: String,
+    id_strategy: IdStrategy,
+}
+
+impl JsonStore {
+    pub fn new(
+        db: Arc<Database>,
+        dirty: Arc<RwLock<Dirty>>,
+        journal: Option<Arc<Mutex<Journal>>>,
+        id: String,
+        id_strategy: IdStrategy,
+    ) -> JsonStore {
+        JsonStore {
+            db,
+            dirty,
+            journal,
+            id,
+            id_strategy,
+        }
+    }
+
+    /// Records a mutation before it is applied, the caller must hold the write lock of what it changes
+    async fn commit(&self, mutation: Mutation) -> StoreResult<()> {
+        let mut dirty = self.dirty.write().await;
+        if let Some(journal) = &self.journal {
+            if let Err(e) = journal.lock().await.append(&mutation).await {
+                log::error!("Error writing journal: {}", e);
+                return Err((
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "error writing journal".to_string(),
+                ));
+            }
+        }
+        dirty.mark(mutation.name());
+        Ok(())
+    }
+
+    async fn collection_of(&self, name: &str) -> Collection {
+        let meta = self.db.get(meta::META_KEY).await;
+        meta::collection(meta.as_deref(), name, &self.id, self.id_strategy)
+    }
+
+    async fn insert_into(
+        &self,
+        name: &str,
+        collection: Collection,
+        entry: &mut Entry,
+        mut value: Value,
+    ) -> StoreResult<Value> {
+        let (items, index) = entry.indexed(&collection)?;
+        assign_id(
+            &mut value,
+            &collection,
+            items
+                .as_array()
+                .and_then(|items| items.first())
+                .map(|item| &item[&collection.id]),
+            |id| index.id.contains(id),
+            || index.id.max_id(),
+        )?;
+        self.commit(Mutation::Insert {
+            name: name.to_string(),
+            field: collection.id,
+            value: value.clone(),
+        })
+        .await?;
+        let items = items_mut(items);
+        index.insert(&value, items.len());
+        items.push(value.clone());
+        Ok(value)
+    }
+}
+
+#[async_trait]
+impl Storage for JsonStore {
+    async fn kind(&self, name: &str) -> StoreResult<Option<Kind>> {
+        Ok(match self.db.get(name).await.as_deref() {
+            Some(Value::Array(_)) => Some(Kind::Array),
+            Some(Value::Null) | None => None,
+            Some(_) => Some(Kind::Value),
+        })
+    }
+
+    async fn collection(&self, name: &str) -> StoreResult<Collection> {
+        Ok(self.collection_of(name).await)
+    }
+
+    async fn query(&self, name: &str, query: Query) -> StoreResult<Option<Page>> {
+        let collection = self.collection_of(name).await;
+        let Some(slot) = self.db.slot(name).await else {
+            return Ok(None);
+        };
+        let mut entry = slot.read().await;
+        if !matches!(&entry.index, Some(index) if index.is_built_for(&collection)) {
+            drop(entry);
+            let mut building = slot.write().await;
+            building.indexed(&collection)?;
+            entry = building.downgrade();
+        }
+        let value = entry.value.clone();
+        let items = items(&value)?;
+        let index = entry.index.as_ref().expect("index is built");
+        let found = index.find(&query.lookups);
+        let sorted = match (&found, &query.sorted_by) {
+            (None, Some((field, ascending))) => index.sorted(field, *ascending, items.len()),
+            _ => None,
+        };
+        drop(entry);
+
+        // filtered and sorted without holding a lock
+        let filter = |item: &&Value| (query.filter)(item);
+        Ok(Some(match (found, sorted) {
+            (Some(positions), _) => {
+                let selected = positions
+                    .par_iter()
+                    .map(|&p| &items[p])
+                    .filter(filter)
+                    .collect();
+                page(selected, query.order.as_ref(), &query.range)
+            }
+            (None, Some(positions)) => {
+                let selected = positions
+                    .par_iter()
+                    .map(|&p| &items[p])
+                    .filter(filter)
+                    .collect();
+                page(selected, None, &query.range)
+            }
+            (None, None) => {
+                let selected = items.par_iter().filter(filter).collect();
+                page(selected, query.order.as_ref(), &query.range)
+            }
+        }))
+    }
+
+    async fn get(&self, name: &str, id: &str) -> StoreResult<Option<Value>> {
+        let collection = self.collection_of(name).await;
+        let Some(slot) = self.db.slot(name).await else {
+            return Ok(None);
+        };
+        let entry = slot.read().await;
+        if let Some(index) = entry
+            .index
+            .as_ref()
+            .filter(|index| index.id.field == collection.id)
+        {
+            return Ok(index
+                .id
+                .position(id)
+                .map(|position| entry.value[position].clone()));
+        }
+        drop(entry);
+        // the first lookup builds the index
+        let mut entry = slot.write().await;
+        Ok(match entry.indexed(&collection) {
+            Ok((items, index)) => index
+                .id
+                .position(id)
+                .map(|position| items[position].clone()),
+            Err(_) => None,
+        })
+    }
+
+    async fn insert(&self, name: &str, value: Value) -> StoreResult<Value> {
+        let collection = self.collection_of(name).await;
+        let keys = self.db.keys.read().await;
+        if let Some(slot) = keys.get(name) {
+            let mut entry = slot.write().await;
+            return self.insert_into(name, collection, &mut entry, value).await;
+        }
+        drop(keys);
+
+        // the first item creates the collection
+        let mut keys = self.db.keys.write().await;
+        let created = !keys.contains_key(name);
+        let slot = keys
+            .entry(name.to_string())
+            .or_insert_with(|| slot(Value::Array(Vec::new())))
+            .clone();
+        let result = self
+            .insert_into(name, collection, &mut *slot.write().await, value)
+            .await;
+        if created {
+            match &result {
+                Ok(_) => log::info!("Array[{}] created", name),
+                Err(_) => {
+                    keys.remove(name);
+                }
+            }
+        }
+        result
+    }
+
+    async fn replace(&self, name: &str, id: &str, update: Update) -> StoreResult<Value> {
+        let collection = self.collection_of(name).await;
+        let keys = self.db.keys.read().await;
+        let mut entry = keys.get(name).ok_or_else(not_found)?.write().await;
+        let (items, index) = entry.indexed(&collection)?;
+        let position = index.id.position(id).ok_or_else(not_found)?;
+        let item = &items[position];
+        let mut value = update(item)?;
+        // the id is immutable
+        value
+            .as_object_mut()
+            .ok_or_else(not_object)?
+            .insert(collection.id.clone(), item[&collection.id].clone());
+        self.commit(Mutation::Update {
+            name: name.to_string(),
+            field: collection.id,
+            value: value.clone(),
+        })
+        .await?;
+        index.update(&items[position], &value);
+        items_mut(items)[position] = value.clone();
+        Ok(value)
+    }
+
+    async fn delete(&self, name: &str, id: &str) -> StoreResult<Value> {
+        let collection = self.collection_of(name).await;
+        let keys = self.db.keys.read().await;
+        let mut entry = keys.get(name).ok_or_else(not_found)?.write().await;
+        let (items, index) = entry.indexed(&collection)?;
+        let position = index.id.position(id).ok_or_else(not_found)?;
+        self.commit(Mutation::Delete {
+            name: name.to_string(),
+            id: items[position][&collection.id].clone(),
+            field: collection.id,
+        })
+        .await?;
+        let items = items_mut(items);
+        let removed = items.remove(position);
+        index.remove(&removed, position);
+        Ok(removed)
+    }
+
+    async fn get_value(&self, name: &str) -> StoreResult<Option<Value>> {
+        Ok(self.db.get(name).await.map(|value| (*value).clone()))
+    }
+
+    async fn set_value(&self, name: &str, update: Update) -> StoreResult<Value> {
+        let keys = self.db.keys.read().await;
+        if let Some(slot) = keys.get(name) {
+            let mut entry = slot.write().await;
+            let value = update(&entry.value)?;
+            self.commit(Mutation::SetValue {
+                name: name.to_string(),
+                value: value.clone(),
+            })
+            .await?;
+            entry.value = Arc::new(value.clone());
+            entry.index = None;
+            return Ok(value);
+        }
+        drop(keys);
+
+        let mut keys = self.db.keys.write().await;
+        let old_value = match keys.get(name) {
+            Some(slot) => slot.read().await.value.clone(),
+            None => Arc::new(Value::Null),
+        };
+        let value = update(&old_value)?;
+        if old_value.is_null() {
+            log::info!("Value[{}] created", name);
+        }
+        self.commit(Mutation::SetValue {
+            name: name.to_string(),
+            value: value.clone(),
+        })
+        .await?;
+        keys.insert(name.to_string(), slot(value.clone()));
+        Ok(value)
+    }
+
+    async fn put(&self, name: &str, value: Value) -> StoreResult<()> {
+        let mut keys = self.db.keys.write().await;
+        self.commit(Mutation::SetValue {
+            name: name.to_string(),
+            value: value.clone(),
+        })
+        .await?;
+        keys.insert(name.to_string(), slot(value));
+        Ok(())
+    }
+
+    async fn remove(&self, name: &str) -> StoreResult<bool> {
+        let mut keys = self.db.keys.write().await;
+        if !keys.contains_key(name) {
+            return Ok(false);
+        }
+        self.commit(Mutation::Remove {
+            name: name.to_string(),
+        })
+        .await?;
+        keys.remove(name);
+        Ok(true)
+    }
+
+    async fn export(&self) -> StoreResult<Map<String, Value>> {
+        let values = self.db.freeze().await.into_values();
+        Ok(values
+            .into_iter()
+            .map(|(name, value)| (name, (*value).clone()))
+            .collect())
+    }
+
+    async fn import(&self, value: Map<String, Value>) -> StoreResult<()> {
+        let mut keys = self.db.keys.write().await;
+        self.commit(Mutation::Replace {
+            value: value.clone(),
+        })
+        .await?;
+        *keys = Database::keys(value);
+        Ok(())
+    }
+}
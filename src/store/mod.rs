@@ -0,0 +1,219 @@
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::ops::{Bound, Range};
+use std::path::Path;
+
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use rayon::prelude::*;
+use serde_json::{Map, Value};
+
+use crate::handler::{id, meta::Collection};
+
+mod index;
+pub mod json;
+pub mod sqlite;
+
+pub use json::{Database, JsonStore};
+pub use sqlite::SqliteStore;
+
+pub type StoreResult<T> = Result<T, (StatusCode, String)>;
+
+/// Decides the new item or value from the current one, so reading and writing it is atomic
+pub type Update = Box<dyn FnOnce(&Value) -> StoreResult<Value> + Send>;
+
+/// Selects the items of a `Query`
+pub type Filter = Box<dyn Fn(&Value) -> bool + Send + Sync>;
+
+/// Orders the selected items of a `Query`
+pub type Compare = Box<dyn Fn(&Value, &Value) -> Ordering + Send + Sync>;
+
+/// Relevance of a selected item of a `Query`
+pub type Rank = Box<dyn Fn(&Value) -> usize + Send + Sync>;
+
+/// How the selected items of a `Query` are sorted, stable
+pub enum Order {
+    By(Compare),
+    /// Highest rank first, computed once per item
+    Rank(Rank),
+}
+
+/// A condition of a `Query` that an index may answer, the filter still checks the items found
+pub enum Lookup {
+    /// `field=value`, `field_in` and repeated `field` parameters, the value is any of `values`
+    Equal { field: String, values: Vec<String> },
+    /// `field_gt`, `field_gte`, `field_lt` and `field_lte`, which hold for numbers in range
+    Range {
+        field: String,
+        lower: Bound<f64>,
+        upper: Bound<f64>,
+    },
+}
+
+/// A listing of a collection
+pub struct Query {
+    pub filter: Filter,
+    pub lookups: Vec<Lookup>,
+    /// Whether `filter` may leave items out, all of them are selected otherwise
+    pub filtered: bool,
+    /// The stored order is kept when `None`
+    pub order: Option<Order>,
+    /// Field and ascending order when `order` sorts by one field alone, so an index may sort instead
+    pub sorted_by: Option<(String, bool)>,
+    /// Range of the sorted items returned, it may reach beyond them
+    pub range: Range<usize>,
+}
+
+/// The items of the range of a `Query`
+pub struct Page {
+    /// Number of selected items
+    pub total: usize,
+    pub items: Vec<Value>,
+}
+
+/// Where the database lives, picked from the extension of the database path unless `--storage` is given
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    /// A json, json5, yaml or toml file or directory held in memory
+    Json,
+    /// An embedded sqlite database, items are json columns
+    Sqlite,
+}
+
+impl StorageKind {
+    pub fn from_path(path: &str) -> StorageKind {
+        match Path::new(path)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .as_deref()
+        {
+            Some("sqlite") | Some("sqlite3") | Some("db") => StorageKind::Sqlite,
+            _ => StorageKind::Json,
+        }
+    }
+}
+
+/// What a top-level key holds
+pub enum Kind {
+    Array,
+    Value,
+}
+
+/// Operations of the api handlers on the database.
+///
+/// Arrays are collections of objects identified by the id field of their `Collection`,
+/// every other top-level key is a value.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// `None` when `name` does not exist
+    async fn kind(&self, name: &str) -> StoreResult<Option<Kind>>;
+
+    /// Effective configuration of collection `name`
+    async fn collection(&self, name: &str) -> StoreResult<Collection>;
+
+    /// `None` when `name` does not exist
+    async fn query(&self, name: &str, query: Query) -> StoreResult<Option<Page>>;
+
+    async fn get(&self, name: &str, id: &str) -> StoreResult<Option<Value>>;
+
+    /// Appends `value` to array `name`, which is created when missing, and generates its id when it has none
+    async fn insert(&self, name: &str, value: Value) -> StoreResult<Value>;
+
+    /// Replaces the item with id `id` by the result of `update`, the item keeps its id
+    async fn replace(&self, name: &str, id: &str, update: Update) -> StoreResult<Value>;
+
+    async fn delete(&self, name: &str, id: &str) -> StoreResult<Value>;
+
+    async fn get_value(&self, name: &str) -> StoreResult<Option<Value>>;
+
+    /// Sets value `name` to the result of `update`, which gets null when `name` does not exist
+    async fn set_value(&self, name: &str, update: Update) -> StoreResult<Value>;
+
+    /// Sets top-level key `name`, arrays included
+    async fn put(&self, name: &str, value: Value) -> StoreResult<()>;
+
+    /// Removes top-level key `name`, returns whether it existed
+    async fn remove(&self, name: &str) -> StoreResult<bool>;
+
+    /// The whole database
+    async fn export(&self) -> StoreResult<Map<String, Value>>;
+
+    /// Replaces the whole database, `db_value` must have passed `check_db`
+    async fn import(&self, db_value: Map<String, Value>) -> StoreResult<()>;
+}
+
+pub fn not_found() -> (StatusCode, String) {
+    (StatusCode::NOT_FOUND, "not found".to_string())
+}
+
+pub fn not_array() -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, "key is not array".to_string())
+}
+
+pub fn not_object() -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, "value is not object".to_string())
+}
+
+/// Sorts the selected items and copies only the returned range of them
+fn page(mut selected: Vec<&Value>, order: Option<&Order>, range: &Range<usize>) -> Page {
+    if let Some(order) = order {
+        sort(&mut selected, order);
+    }
+    let total = selected.len();
+    let start = range.start.min(total);
+    let end = range.end.clamp(start, total);
+    Page {
+        total,
+        items: selected[start..end]
+            .iter()
+            .map(|&item| item.clone())
+            .collect(),
+    }
+}
+
+/// Sorts `items` by `order`, items sorted equal keep their order
+fn sort<T: Borrow<Value> + Send>(items: &mut Vec<T>, order: &Order) {
+    match order {
+        Order::By(compare) => items.par_sort_by(|a, b| compare(a.borrow(), b.borrow())),
+        Order::Rank(rank) => {
+            let mut ranked = std::mem::take(items)
+                .into_par_iter()
+                .map(|item| (rank(item.borrow()), item))
+                .collect::<Vec<(usize, T)>>();
+            ranked.par_sort_by(|a, b| b.0.cmp(&a.0));
+            *items = ranked.into_iter().map(|(_, item)| item).collect();
+        }
+    }
+}
+
+/// Checks the id of an item posted with one against the id of the first item of its collection,
+/// or generates one
+fn assign_id(
+    value: &mut Value,
+    collection: &Collection,
+    first_id: Option<&Value>,
+    exists: impl FnOnce(&Value) -> bool,
+    max_id: impl FnOnce() -> u64,
+) -> StoreResult<()> {
+    match value.get(&collection.id) {
+        Some(id) => {
+            if let Some(first_id) = first_id {
+                if !id::same_kind(first_id, id) {
+                    return Err((StatusCode::BAD_REQUEST, "id type mismatch".to_string()));
+                }
+            }
+            if exists(id) {
+                return Err((StatusCode::BAD_REQUEST, "id exists".to_string()));
+            }
+        }
+        None => {
+            let new_id = id::generate(collection.id_strategy, first_id, max_id)
+                .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+            value
+                .as_object_mut()
+                .ok_or_else(not_object)?
+                .insert(collection.id.clone(), new_id);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,577 @@
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::{Map, Value};
+
+use super::{
+    assign_id, not_array, not_found, not_object, page, sort, Kind, Page, Query, Storage,
+    StoreResult, Update,
+};
+use crate::handler::{
+    id::IdStrategy,
+    meta::{self, Collection},
+};
+
+/// Least number of items kept while sorting a query, sorting fewer at a time gains nothing
+const SORT_BUFFER: usize = 1024;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS key_values (
+    name TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS collections (
+    name TEXT PRIMARY KEY
+);
+CREATE TABLE IF NOT EXISTS items (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    collection TEXT NOT NULL,
+    id TEXT NOT NULL,
+    value TEXT NOT NULL,
+    UNIQUE (collection, id)
+);
+";
+
+/// An embedded sqlite database: values are json text in `key_values`, the items of every
+/// collection are json text in `items`, keyed by the json text of their id, in insertion order.
+///
+/// Items are read one row at a time, a list request holds the items of its range,
+/// and when sorting, the items in order up to the end of its range.
+pub struct SqliteStore {
+    database: Arc<Mutex<Database>>,
+}
+
+struct Database {
+    connection: Connection,
+    id: String,
+    id_strategy: IdStrategy,
+}
+
+impl SqliteStore {
+    pub fn open(path: &str, id: String, id_strategy: IdStrategy) -> rusqlite::Result<SqliteStore> {
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "FULL")?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(SqliteStore {
+            database: Arc::new(Mutex::new(Database {
+                connection,
+                id,
+                id_strategy,
+            })),
+        })
+    }
+
+    /// Whether the database holds nothing, so a seed can be imported
+    pub async fn is_empty(&self) -> StoreResult<bool> {
+        self.call(|database| {
+            let count: i64 = database
+                .connection
+                .query_row(
+                    "SELECT (SELECT COUNT(*) FROM key_values) + (SELECT COUNT(*) FROM collections)",
+                    [],
+                    |row| row.get(0),
+                )
+                .map_err(sql_error)?;
+            Ok(count == 0)
+        })
+        .await
+    }
+
+    /// Runs `f` on the blocking thread pool, sqlite calls block
+    async fn call<T, F>(&self, f: F) -> StoreResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Database) -> StoreResult<T> + Send + 'static,
+    {
+        let database = self.database.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut database = database.lock().unwrap_or_else(|e| e.into_inner());
+            f(&mut database)
+        })
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("sqlite task error: {}", e),
+            )
+        })?
+    }
+}
+
+impl Database {
+    fn collection(&self, name: &str) -> StoreResult<Collection> {
+        let meta = read_value(&self.connection, meta::META_KEY)?;
+        Ok(meta::collection(
+            meta.as_ref(),
+            name,
+            &self.id,
+            self.id_strategy,
+        ))
+    }
+}
+
+fn sql_error(e: rusqlite::Error) -> (StatusCode, String) {
+    log::error!("Sqlite error: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("sqlite error: {}", e),
+    )
+}
+
+fn parse(content: &str) -> StoreResult<Value> {
+    serde_json::from_str(content).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("parse stored value error: {}", e),
+        )
+    })
+}
+
+/// Key of an item id in `items`
+fn id_key(id: &Value) -> String {
+    id.to_string()
+}
+
+/// Keys an id taken from the request path may have, as a string id or as an integer id
+fn path_id_keys(id: &str) -> (String, String) {
+    let string_key = id_key(&Value::String(id.to_string()));
+    match id.parse::<u64>() {
+        Ok(number) => (string_key, number.to_string()),
+        Err(_) => (string_key.clone(), string_key),
+    }
+}
+
+fn read_value(connection: &Connection, name: &str) -> StoreResult<Option<Value>> {
+    let content = connection
+        .query_row(
+            "SELECT value FROM key_values WHERE name = ?1",
+            params![name],
+            |row| row.get::<_, String>(0),
+        )
+        .optional()
+        .map_err(sql_error)?;
+    content.as_deref().map(parse).transpose()
+}
+
+fn is_collection(connection: &Connection, name: &str) -> StoreResult<bool> {
+    connection
+        .query_row(
+            "SELECT 1 FROM collections WHERE name = ?1",
+            params![name],
+            |_| Ok(()),
+        )
+        .optional()
+        .map(|found| found.is_some())
+        .map_err(sql_error)
+}
+
+/// Calls `f` with every item of collection `name` in insertion order
+fn for_each_item(connection: &Connection, name: &str, mut f: impl FnMut(Value)) -> StoreResult<()> {
+    let mut statement = connection
+        .prepare_cached("SELECT value FROM items WHERE collection = ?1 ORDER BY seq")
+        .map_err(sql_error)?;
+    let rows = statement
+        .query_map(params![name], |row| row.get::<_, String>(0))
+        .map_err(sql_error)?;
+    for content in rows {
+        f(parse(&content.map_err(sql_error)?)?);
+    }
+    Ok(())
+}
+
+/// The items of `range` of a collection and its number of items, read without the others
+fn page_rows(connection: &Connection, name: &str, range: &Range<usize>) -> StoreResult<Page> {
+    let total: i64 = connection
+        .query_row(
+            "SELECT COUNT(*) FROM items WHERE collection = ?1",
+            params![name],
+            |row| row.get(0),
+        )
+        .map_err(sql_error)?;
+    let limit = range.end.saturating_sub(range.start);
+    let mut statement = connection
+        .prepare_cached(
+            "SELECT value FROM items WHERE collection = ?1 ORDER BY seq LIMIT ?2 OFFSET ?3",
+        )
+        .map_err(sql_error)?;
+    let rows = statement
+        .query_map(
+            params![
+                name,
+                i64::try_from(limit).unwrap_or(i64::MAX),
+                i64::try_from(range.start).unwrap_or(i64::MAX)
+            ],
+            |row| row.get::<_, String>(0),
+        )
+        .map_err(sql_error)?;
+    let mut items = Vec::new();
+    for content in rows {
+        items.push(parse(&content.map_err(sql_error)?)?);
+    }
+    Ok(Page {
+        total: total as usize,
+        items,
+    })
+}
+
+fn items(connection: &Connection, name: &str) -> StoreResult<Vec<Value>> {
+    let mut items = Vec::new();
+    for_each_item(connection, name, |item| items.push(item))?;
+    Ok(items)
+}
+
+fn find_item(connection: &Connection, name: &str, id: &str) -> StoreResult<Option<(i64, Value)>> {
+    let (string_key, number_key) = path_id_keys(id);
+    let row = connection
+        .query_row(
+            "SELECT seq, value FROM items WHERE collection = ?1 AND id IN (?2, ?3)",
+            params![name, string_key, number_key],
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+        )
+        .optional()
+        .map_err(sql_error)?;
+    match row {
+        Some((seq, content)) => Ok(Some((seq, parse(&content)?))),
+        None => Ok(None),
+    }
+}
+
+fn insert_item(connection: &Connection, name: &str, id: &str, item: &Value) -> StoreResult<()> {
+    connection
+        .execute(
+            "INSERT INTO items (collection, id, value) VALUES (?1, ?2, ?3)",
+            params![name, id_key(&item[id]), item.to_string()],
+        )
+        .map_err(sql_error)?;
+    Ok(())
+}
+
+fn remove_key(connection: &Connection, name: &str) -> StoreResult<bool> {
+    let mut removed = 0;
+    for sql in [
+        "DELETE FROM key_values WHERE name = ?1",
+        "DELETE FROM collections WHERE name = ?1",
+        "DELETE FROM items WHERE collection = ?1",
+    ] {
+        removed += connection.execute(sql, params![name]).map_err(sql_error)?;
+    }
+    Ok(removed > 0)
+}
+
+/// Sets top-level key `name`, the items of an array are identified by field `id`
+fn put_key(connection: &Connection, name: &str, value: &Value, id: &str) -> StoreResult<()> {
+    remove_key(connection, name)?;
+    match value {
+        Value::Array(items) => {
+            connection
+                .execute("INSERT INTO collections (name) VALUES (?1)", params![name])
+                .map_err(sql_error)?;
+            for item in items {
+                insert_item(connection, name, id, item)?;
+            }
+        }
+        _ => {
+            connection
+                .execute(
+                    "INSERT INTO key_values (name, value) VALUES (?1, ?2)",
+                    params![name, value.to_string()],
+                )
+                .map_err(sql_error)?;
+        }
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Storage for SqliteStore {
+    async fn kind(&self, name: &str) -> StoreResult<Option<Kind>> {
+        let name = name.to_string();
+        self.call(move |database| {
+            if is_collection(&database.connection, &name)? {
+                return Ok(Some(Kind::Array));
+            }
+            Ok(match read_value(&database.connection, &name)? {
+                Some(Value::Null) | None => None,
+                Some(_) => Some(Kind::Value),
+            })
+        })
+        .await
+    }
+
+    async fn collection(&self, name: &str) -> StoreResult<Collection> {
+        let name = name.to_string();
+        self.call(move |database| database.collection(&name)).await
+    }
+
+    async fn query(&self, name: &str, query: Query) -> StoreResult<Option<Page>> {
+        let name = name.to_string();
+        self.call(move |database| {
+            if !is_collection(&database.connection, &name)? {
+                return match read_value(&database.connection, &name)? {
+                    Some(_) => Err(not_array()),
+                    None => Ok(None),
+                };
+            }
+            // lookups are only answered by the indexes of the json store
+            let Some(order) = query.order.as_ref() else {
+                if !query.filtered {
+                    return page_rows(&database.connection, &name, &query.range).map(Some);
+                }
+                // only the items of the range are kept
+                let mut total = 0;
+                let mut items = Vec::new();
+                for_each_item(&database.connection, &name, |item| {
+                    if (query.filter)(&item) {
+                        if query.range.contains(&total) {
+                            items.push(item);
+                        }
+                        total += 1;
+                    }
+                })?;
+                return Ok(Some(Page { total, items }));
+            };
+            // only the first items in order up to the end of the range are kept, sorting
+            // whenever twice as many are held
+            let limit = query.range.end.max(SORT_BUFFER);
+            let mut total = 0;
+            let mut selected = Vec::new();
+            for_each_item(&database.connection, &name, |item| {
+                if (query.filter)(&item) {
+                    total += 1;
+                    selected.push(item);
+                    if selected.len() >= limit.saturating_mul(2) {
+                        // stable, kept items stay ahead of equal items read later
+                        sort(&mut selected, order);
+                        selected.truncate(limit);
+                    }
+                }
+            })?;
+            let mut page = page(selected.iter().collect(), Some(order), &query.range);
+            page.total = total;
+            Ok(Some(page))
+        })
+        .await
+    }
+
+    async fn get(&self, name: &str, id: &str) -> StoreResult<Option<Value>> {
+        let (name, id) = (name.to_string(), id.to_string());
+        self.call(move |database| {
+            Ok(find_item(&database.connection, &name, &id)?.map(|(_, item)| item))
+        })
+        .await
+    }
+
+    async fn insert(&self, name: &str, mut value: Value) -> StoreResult<Value> {
+        let name = name.to_string();
+        self.call(move |database| {
+            let collection = database.collection(&name)?;
+            let transaction = database.connection.transaction().map_err(sql_error)?;
+            if !is_collection(&transaction, &name)? {
+                if read_value(&transaction, &name)?.is_some() {
+                    return Err(not_array());
+                }
+                transaction
+                    .execute("INSERT INTO collections (name) VALUES (?1)", params![name])
+                    .map_err(sql_error)?;
+                log::info!("Array[{}] created", name);
+            }
+            let first_id = transaction
+                .query_row(
+                    "SELECT id FROM items WHERE collection = ?1 ORDER BY seq LIMIT 1",
+                    params![name],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()
+                .map_err(sql_error)?
+                .as_deref()
+                .map(parse)
+                .transpose()?;
+            let mut exists = Ok(false);
+            let mut max_id = Ok(0);
+            assign_id(
+                &mut value,
+                &collection,
+                first_id.as_ref(),
+                |id| {
+                    exists = transaction
+                        .query_row(
+                            "SELECT 1 FROM items WHERE collection = ?1 AND id = ?2",
+                            params![name, id_key(id)],
+                            |_| Ok(()),
+                        )
+                        .optional()
+                        .map(|found| found.is_some());
+                    *exists.as_ref().unwrap_or(&true)
+                },
+                || {
+                    max_id = transaction.query_row(
+                        "SELECT COALESCE(MAX(CAST(id AS INTEGER)), 0) FROM items WHERE collection = ?1",
+                        params![name],
+                        |row| row.get::<_, i64>(0),
+                    );
+                    *max_id.as_ref().unwrap_or(&0) as u64
+                },
+            )?;
+            exists.map_err(sql_error)?;
+            max_id.map_err(sql_error)?;
+            insert_item(&transaction, &name, &collection.id, &value)?;
+            transaction.commit().map_err(sql_error)?;
+            Ok(value)
+        })
+        .await
+    }
+
+    async fn replace(&self, name: &str, id: &str, update: Update) -> StoreResult<Value> {
+        let (name, id) = (name.to_string(), id.to_string());
+        self.call(move |database| {
+            let collection = database.collection(&name)?;
+            if !is_collection(&database.connection, &name)? {
+                return Err(not_found());
+            }
+            let (seq, item) = find_item(&database.connection, &name, &id)?.ok_or_else(not_found)?;
+            let mut value = update(&item)?;
+            // the id is immutable
+            value
+                .as_object_mut()
+                .ok_or_else(not_object)?
+                .insert(collection.id.clone(), item[&collection.id].clone());
+            database
+                .connection
+                .execute(
+                    "UPDATE items SET value = ?1 WHERE seq = ?2",
+                    params![value.to_string(), seq],
+                )
+                .map_err(sql_error)?;
+            Ok(value)
+        })
+        .await
+    }
+
+    async fn delete(&self, name: &str, id: &str) -> StoreResult<Value> {
+        let (name, id) = (name.to_string(), id.to_string());
+        self.call(move |database| {
+            let (seq, item) = find_item(&database.connection, &name, &id)?.ok_or_else(not_found)?;
+            database
+                .connection
+                .execute("DELETE FROM items WHERE seq = ?1", params![seq])
+                .map_err(sql_error)?;
+            Ok(item)
+        })
+        .await
+    }
+
+    async fn get_value(&self, name: &str) -> StoreResult<Option<Value>> {
+        let name = name.to_string();
+        self.call(move |database| {
+            if is_collection(&database.connection, &name)? {
+                return Ok(Some(Value::Array(items(&database.connection, &name)?)));
+            }
+            read_value(&database.connection, &name)
+        })
+        .await
+    }
+
+    async fn set_value(&self, name: &str, update: Update) -> StoreResult<Value> {
+        let name = name.to_string();
+        self.call(move |database| {
+            if is_collection(&database.connection, &name)? {
+                return Err((StatusCode::BAD_REQUEST, "key is array".to_string()));
+            }
+            let old_value = read_value(&database.connection, &name)?;
+            let value = update(old_value.as_ref().unwrap_or(&Value::Null))?;
+            if old_value.is_none() {
+                log::info!("Value[{}] created", name);
+            }
+            database
+                .connection
+                .execute(
+                    "INSERT OR REPLACE INTO key_values (name, value) VALUES (?1, ?2)",
+                    params![name, value.to_string()],
+                )
+                .map_err(sql_error)?;
+            Ok(value)
+        })
+        .await
+    }
+
+    async fn put(&self, name: &str, value: Value) -> StoreResult<()> {
+        let name = name.to_string();
+        self.call(move |database| {
+            let collection = database.collection(&name)?;
+            let transaction = database.connection.transaction().map_err(sql_error)?;
+            put_key(&transaction, &name, &value, &collection.id)?;
+            transaction.commit().map_err(sql_error)
+        })
+        .await
+    }
+
+    async fn remove(&self, name: &str) -> StoreResult<bool> {
+        let name = name.to_string();
+        self.call(move |database| {
+            let transaction = database.connection.transaction().map_err(sql_error)?;
+            let removed = remove_key(&transaction, &name)?;
+            transaction.commit().map_err(sql_error)?;
+            Ok(removed)
+        })
+        .await
+    }
+
+    async fn export(&self) -> StoreResult<Map<String, Value>> {
+        self.call(|database| {
+            let connection = &database.connection;
+            let mut db_value = Map::new();
+            let mut statement = connection
+                .prepare("SELECT name, value FROM key_values")
+                .map_err(sql_error)?;
+            let rows = statement
+                .query_map([], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                })
+                .map_err(sql_error)?;
+            for row in rows {
+                let (name, content) = row.map_err(sql_error)?;
+                db_value.insert(name, parse(&content)?);
+            }
+            let mut statement = connection
+                .prepare("SELECT name FROM collections")
+                .map_err(sql_error)?;
+            let names = statement
+                .query_map([], |row| row.get::<_, String>(0))
+                .map_err(sql_error)?
+                .collect::<Result<Vec<String>, _>>()
+                .map_err(sql_error)?;
+            for name in names {
+                let items = items(connection, &name)?;
+                db_value.insert(name, Value::Array(items));
+            }
+            Ok(db_value)
+        })
+        .await
+    }
+
+    async fn import(&self, db_value: Map<String, Value>) -> StoreResult<()> {
+        self.call(move |database| {
+            let transaction = database.connection.transaction().map_err(sql_error)?;
+            transaction
+                .execute_batch(
+                    "DELETE FROM key_values; DELETE FROM collections; DELETE FROM items;",
+                )
+                .map_err(sql_error)?;
+            for (name, value) in db_value.iter() {
+                let collection = meta::collection(
+                    db_value.get(meta::META_KEY),
+                    name,
+                    &database.id,
+                    database.id_strategy,
+                );
+                put_key(&transaction, name, value, &collection.id)?;
+            }
+            transaction.commit().map_err(sql_error)
+        })
+        .await
+    }
+}
@@ -0,0 +1,108 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde_json::Value;
+
+use crate::{handler, persist, persist::Dirty, store::Database, AppState};
+
+/// Wait for editors to finish writing before reloading
+const DEBOUNCE_MILLIS: u64 = 200;
+
+pub fn content_hash(content: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Watches the database file and reloads it when it is changed by someone else.
+/// The returned watcher must be kept alive for as long as reloading is wanted.
+pub fn watch(app_state: AppState, db_path: String) -> notify::Result<RecommendedWatcher> {
+    let file_name = Path::new(&db_path)
+        .file_name()
+        .map(|name| name.to_os_string());
+    let dir = persist::parent_dir(&db_path).to_path_buf();
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<()>();
+    // the directory is watched because saving renames a new file over the old one
+    let mut watcher =
+        notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
+            Ok(event) => {
+                if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    return;
+                }
+                if event
+                    .paths
+                    .iter()
+                    .any(|p| p.file_name().map(|name| name.to_os_string()) == file_name)
+                {
+                    let _ = tx.send(());
+                }
+            }
+            Err(e) => log::error!("Error watching database file: {}", e),
+        })?;
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+
+    tokio::spawn(async move {
+        while rx.recv().await.is_some() {
+            tokio::time::sleep(tokio::time::Duration::from_millis(DEBOUNCE_MILLIS)).await;
+            while rx.try_recv().is_ok() {}
+            reload(&app_state, &db_path).await;
+        }
+    });
+    log::info!("Watching database file for changes");
+    Ok(watcher)
+}
+
+async fn reload(app_state: &AppState, db_path: &str) {
+    let db_content = match tokio::fs::read_to_string(db_path).await {
+        Ok(content) => content,
+        Err(e) => {
+            log::warn!("Error reading changed database file: {}", e);
+            return;
+        }
+    };
+    let hash = content_hash(&db_content);
+    if hash == *app_state.db_hash.read().await {
+        log::trace!("Database file unchanged, reload skipped");
+        return;
+    }
+    if app_state.format.has_comments(&db_content) {
+        log::warn!("Comments in the database file are not kept when it is saved");
+    }
+    let db_value = match app_state.format.parse(&db_content) {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!(
+                "Error parsing changed database file, reload rejected: {}",
+                e
+            );
+            return;
+        }
+    };
+    if let Err(e) = handler::check_db(&db_value, &app_state.id) {
+        log::error!("Invalid changed database file, reload rejected: {}", e);
+        return;
+    }
+    let db_value = match db_value {
+        Value::Object(db_value) => db_value,
+        _ => unreachable!("checked by check_db"),
+    };
+
+    let mut keys = app_state.db.write().await;
+    let mut dirty = app_state.dirty.write().await;
+    if dirty.is_dirty() {
+        log::warn!("Unsaved changes are discarded by reloading");
+    }
+    if let Some(journal) = &app_state.journal {
+        if let Err(e) = journal.lock().await.reset().await {
+            log::error!("Error resetting journal, reload rejected: {}", e);
+            return;
+        }
+    }
+    *keys = Database::keys(db_value);
+    *dirty = Dirty::default();
+    *app_state.db_hash.write().await = hash;
+    log::info!("Database file reloaded");
+}